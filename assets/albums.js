@@ -0,0 +1,38 @@
+// adding files to albums and taking them out again, delegated like tags.js
+(() => {
+  const edit = async (editor, method, album) => {
+    const response = await send(editor.dataset.url, method, { album });
+
+    if (response) {
+      editor.outerHTML = (await response.json()).html;
+    }
+  };
+
+  document.addEventListener("click", async (event) => {
+    const button = event.target.closest("button");
+    const editor = button?.closest(".albums");
+
+    if (!button) {
+      return;
+    }
+
+    if (editor && button.classList.contains("album-add")) {
+      // culling usually goes into the same album over and over
+      const album = prompt("Add to album", localStorage.getItem("iv-album") || "");
+      if (album) {
+        localStorage.setItem("iv-album", album);
+        await edit(editor, "POST", album);
+      }
+    } else if (editor && button.classList.contains("album-remove")) {
+      await edit(editor, "DELETE", button.dataset.album);
+    } else if (button.classList.contains("album-delete")) {
+      const album = button.closest(".album").dataset.album;
+      if (
+        confirm(`Delete the album "${album}"? The files themselves stay where they are`) &&
+        (await send(`/!albums/${encodeURIComponent(album)}`, "DELETE", {}))
+      ) {
+        location.reload();
+      }
+    }
+  });
+})();
@@ -0,0 +1,65 @@
+// infinite scroll, swaps the pager for the next page of cards once it scrolls into view
+(() => {
+  const grid = document.querySelector(".entry-grid");
+  const pager = document.querySelector(".pager");
+
+  if (!grid || !pager) {
+    return;
+  }
+
+  let next = pager.dataset.next;
+  let loading = false;
+
+  // from here on the script does the paging, the links are only for when it cant
+  pager.replaceChildren();
+
+  const load = async () => {
+    if (loading || !next) {
+      return;
+    }
+
+    loading = true;
+
+    try {
+      const response = await fetch(`${next}&fragment=true`);
+
+      if (!response.ok) {
+        // stop scrolling and leave a link to the page that wouldnt load
+        observer.disconnect();
+        const link = document.createElement("a");
+        link.href = next;
+        link.textContent = "next page";
+        pager.replaceChildren(link);
+        next = null;
+        return;
+      }
+
+      const fragment = await response.json();
+
+      grid.insertAdjacentHTML("beforeend", fragment.html);
+      next = fragment.next;
+    } finally {
+      loading = false;
+    }
+
+    if (next) {
+      // re-observing fires the callback again if the pager is still on screen
+      observer.unobserve(pager);
+      observer.observe(pager);
+    } else {
+      observer.disconnect();
+      pager.remove();
+    }
+  };
+
+  const observer = new IntersectionObserver(
+    (entries) => {
+      if (entries.some((entry) => entry.isIntersecting)) {
+        load();
+      }
+    },
+    { rootMargin: "800px" }
+  );
+
+  observer.observe(pager);
+})();
@@ -0,0 +1,62 @@
+// live reload, keeps the grid in sync with the directory as files come and go
+(() => {
+  const live = document.querySelector(".live");
+  const grid = document.querySelector(".entry-grid");
+
+  if (!live || !grid || !window.EventSource) {
+    return;
+  }
+
+  // names can be anything, so compare datasets instead of building a selector
+  const find = (name) =>
+    [...grid.querySelectorAll(":scope > .entry")].find(
+      (card) => card.dataset.name === name
+    );
+
+  const remove = (name) => find(name)?.remove();
+
+  const update = async (name) => {
+    const separator = live.dataset.card.includes("?") ? "&" : "?";
+    const response = await fetch(
+      `${live.dataset.card}${separator}name=${encodeURIComponent(name)}`
+    );
+    const card = await response.json();
+
+    remove(name);
+
+    if (!card.html) {
+      return;
+    }
+
+    const before = card.before && find(card.before);
+
+    if (before) {
+      before.insertAdjacentHTML("beforebegin", card.html);
+    } else if (!document.querySelector(".pager")) {
+      // while theres a pager, a card without a loaded successor belongs to a later page
+      grid.insertAdjacentHTML("beforeend", card.html);
+    }
+  };
+
+  // files being written fire a burst of events, only act once they settle down
+  const pending = new Map();
+  const schedule = (name, action) => {
+    clearTimeout(pending.get(name));
+    pending.set(
+      name,
+      setTimeout(() => {
+        pending.delete(name);
+        action(name);
+      }, 300)
+    );
+  };
+
+  const events = new EventSource(live.dataset.events);
+
+  events.addEventListener("update", (event) =>
+    schedule(JSON.parse(event.data).name, update)
+  );
+  events.addEventListener("remove", (event) =>
+    schedule(JSON.parse(event.data).name, remove)
+  );
+})();
@@ -0,0 +1,60 @@
+// star ratings and favourites, by clicking or with 0-5 and f on the viewer or the hovered card
+(() => {
+  const rate = async (rating, params) => {
+    const response = await send(rating.dataset.url, "POST", params);
+
+    if (response) {
+      rating.outerHTML = (await response.json()).html;
+    }
+  };
+
+  const toggleFavourite = (rating) =>
+    rate(rating, { favourite: rating.dataset.favourite !== "true" });
+
+  // picking the current rating again clears it
+  const setStars = (rating, stars) =>
+    rate(rating, { rating: rating.dataset.rating === stars ? 0 : stars });
+
+  document.addEventListener("click", (event) => {
+    const button = event.target.closest("button");
+    const rating = button?.closest(".rating");
+
+    if (!rating) {
+      return;
+    }
+
+    if (button.classList.contains("favourite")) {
+      toggleFavourite(rating);
+    } else if (button.dataset.stars) {
+      setStars(rating, button.dataset.stars);
+    }
+  });
+
+  document.addEventListener("keydown", (event) => {
+    if (
+      event.altKey ||
+      event.ctrlKey ||
+      event.metaKey ||
+      event.target.closest("input, select, textarea")
+    ) {
+      return;
+    }
+
+    const rating =
+      document.querySelector(".viewer .rating") ||
+      document.querySelector(".entry:hover .rating");
+
+    if (!rating) {
+      return;
+    }
+
+    if (event.key === "f") {
+      event.preventDefault();
+      toggleFavourite(rating);
+    } else if (/^[0-5]$/.test(event.key)) {
+      event.preventDefault();
+      // 0 always clears, so it doesnt toggle like clicking does
+      rate(rating, { rating: event.key });
+    }
+  });
+})();
@@ -0,0 +1,217 @@
+// paging for the reader, and telling the server where we got to so the next visit resumes there
+(() => {
+  const reader = document.querySelector(".reader");
+
+  if (!reader) {
+    return;
+  }
+
+  const pages = [...reader.querySelectorAll(".reader-page")];
+  const position = reader.querySelector(".reader-position");
+
+  let mode = reader.dataset.mode;
+  let rtl = reader.dataset.rtl === "true";
+  let current = Math.min(Number(reader.dataset.page) || 0, pages.length - 1);
+
+  // which pages show together in spread mode, the cover and wide pages stand alone
+  const alone = (i) => i === 0 || "wide" in pages[i].dataset;
+
+  const spreads = () => {
+    const groups = [];
+
+    pages.forEach((_, i) => {
+      const last = groups[groups.length - 1];
+
+      if (!alone(i) && last?.length === 1 && !alone(last[0])) {
+        last.push(i);
+      } else {
+        groups.push([i]);
+      }
+    });
+
+    return groups;
+  };
+
+  const groups = () => (mode === "spread" ? spreads() : pages.map((_, i) => [i]));
+
+  const groupOf = (i) => {
+    const all = groups();
+    return [all, all.findIndex((group) => group.includes(i))];
+  };
+
+  // saving is debounced, paging through quickly only needs the last page stored
+  let timer = null;
+
+  const params = () =>
+    new URLSearchParams({ page: pages[current].dataset.name, mode, rtl });
+
+  const save = () => {
+    clearTimeout(timer);
+    timer = setTimeout(() => {
+      timer = null;
+      fetch(reader.dataset.url, { method: "POST", body: params() });
+    }, 500);
+  };
+
+  // a save still waiting when the tab goes away would be lost otherwise
+  window.addEventListener("pagehide", () => {
+    if (timer) {
+      clearTimeout(timer);
+      navigator.sendBeacon(reader.dataset.url, params());
+    }
+  });
+
+  const showPosition = () => {
+    const [all, index] = groupOf(current);
+    const shown = all[index];
+    const first = shown[0] + 1;
+    const last = shown[shown.length - 1] + 1;
+
+    position.textContent = `${first === last ? first : `${first}-${last}`} / ${pages.length}`;
+  };
+
+  const render = () => {
+    reader.dataset.mode = mode;
+    reader.classList.toggle("rtl", rtl);
+
+    for (const button of reader.querySelectorAll("[data-mode]")) {
+      button.classList.toggle("active", button.dataset.mode === mode);
+    }
+
+    reader.querySelector(".rtl").classList.toggle("active", rtl);
+
+    if (mode === "scroll") {
+      pages.forEach((page) => page.classList.remove("shown"));
+      pages[current].scrollIntoView();
+    } else {
+      const [all, index] = groupOf(current);
+
+      pages.forEach((page, i) => page.classList.toggle("shown", all[index].includes(i)));
+
+      // warm the cache for the next pages, they load lazily otherwise
+      for (const i of all[index + 1] || []) {
+        new Image().src = pages[i].src;
+      }
+    }
+
+    showPosition();
+  };
+
+  const go = (target) => {
+    target = Math.max(0, Math.min(target, pages.length - 1));
+
+    if (mode === "scroll") {
+      pages[target].scrollIntoView({ behavior: "smooth" });
+      return;
+    }
+
+    if (target !== current) {
+      current = target;
+      render();
+      save();
+    }
+  };
+
+  // steps a whole spread at a time
+  const step = (by) => {
+    const [all, index] = groupOf(current);
+    const group = all[Math.max(0, Math.min(index + by, all.length - 1))];
+
+    go(group[0]);
+  };
+
+  // pages without known dimensions move around as they load, so the bookmark only follows
+  // the scroll position once its the reader doing the scrolling
+  let scrolled = false;
+
+  for (const type of ["wheel", "touchmove", "pointerdown", "keydown"]) {
+    document.addEventListener(type, () => (scrolled = true), { once: true });
+  }
+
+  // in scroll mode the page across the middle of the screen is the current one
+  const observer = new IntersectionObserver(
+    (entries) => {
+      if (mode !== "scroll" || !scrolled) {
+        return;
+      }
+
+      for (const entry of entries) {
+        if (entry.isIntersecting) {
+          current = pages.indexOf(entry.target);
+          showPosition();
+          save();
+        }
+      }
+    },
+    { rootMargin: "-50% 0px" }
+  );
+
+  pages.forEach((page) => observer.observe(page));
+
+  reader.querySelector(".reader-bar").addEventListener("click", (event) => {
+    const button = event.target.closest("button");
+
+    if (!button) {
+      return;
+    }
+
+    if (button.dataset.mode) {
+      mode = button.dataset.mode;
+    } else {
+      rtl = !rtl;
+    }
+
+    // spreads pair pages differently, so start the spread the current page is in
+    if (mode === "spread") {
+      const [all, index] = groupOf(current);
+      current = all[index][0];
+    }
+
+    render();
+    save();
+  });
+
+  // clicking the left or right half of the pages turns towards that side
+  reader.querySelector(".reader-pages").addEventListener("click", (event) => {
+    if (mode === "scroll") {
+      return;
+    }
+
+    const bounds = event.currentTarget.getBoundingClientRect();
+    const left = event.clientX < bounds.left + bounds.width / 2;
+
+    step(left === rtl ? 1 : -1);
+  });
+
+  document.addEventListener("keydown", (event) => {
+    if (event.altKey || event.ctrlKey || event.metaKey) {
+      return;
+    }
+
+    // in right to left the left arrow is the next page, like turning a manga
+    const forward = rtl ? "ArrowLeft" : "ArrowRight";
+    const back = rtl ? "ArrowRight" : "ArrowLeft";
+
+    const paged = mode !== "scroll";
+
+    const actions = {
+      [forward]: paged && (() => step(1)),
+      [back]: paged && (() => step(-1)),
+      " ": paged && (() => step(event.shiftKey ? -1 : 1)),
+      PageDown: paged && (() => step(1)),
+      PageUp: paged && (() => step(-1)),
+      Home: () => go(0),
+      End: () => go(pages.length - 1),
+      Escape: () => (window.location.href = reader.dataset.up),
+    };
+
+    const action = actions[event.key];
+
+    if (action) {
+      event.preventDefault();
+      action();
+    }
+  });
+
+  render();
+})();
@@ -0,0 +1,16 @@
+// form posts for the editors in tags.js, albums.js and rating.js. DELETE carries its params in
+// the query string. failures are shown to the user and come back as null
+const send = async (url, method, params) => {
+  const body = new URLSearchParams(params);
+  const response =
+    method === "DELETE"
+      ? await fetch(`${url}?${body}`, { method })
+      : await fetch(url, { method, body });
+
+  if (!response.ok) {
+    alert(await response.text());
+    return null;
+  }
+
+  return response;
+};
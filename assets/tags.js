@@ -0,0 +1,42 @@
+// tag editing on cards, in the viewer and on the tag index.
+// cards come and go with paging and live reload, so everything is delegated from the document
+(() => {
+  // the server sends back the re-rendered editor
+  const edit = async (editor, method, tag) => {
+    const response = await send(editor.dataset.url, method, { tag });
+
+    if (response) {
+      editor.outerHTML = (await response.json()).html;
+    }
+  };
+
+  const tagUrl = (tag) => `/!tags/${encodeURIComponent(tag)}`;
+
+  document.addEventListener("click", async (event) => {
+    const button = event.target.closest("button");
+    const editor = button?.closest(".tags");
+    const tag = button?.closest(".tag")?.dataset.tag;
+
+    if (!button) {
+      return;
+    }
+
+    if (editor && button.classList.contains("tag-add")) {
+      const tag = prompt("Add tag");
+      if (tag) {
+        await edit(editor, "POST", tag);
+      }
+    } else if (editor && button.classList.contains("tag-remove")) {
+      await edit(editor, "DELETE", button.dataset.tag);
+    } else if (button.classList.contains("tag-rename")) {
+      const to = prompt(`Rename "${tag}" to`, tag);
+      if (to && to !== tag && (await send(tagUrl(tag), "PUT", { to }))) {
+        location.reload();
+      }
+    } else if (button.classList.contains("tag-delete")) {
+      if (confirm(`Remove "${tag}" from every file?`) && (await send(tagUrl(tag), "DELETE", {}))) {
+        location.reload();
+      }
+    }
+  });
+})();
@@ -0,0 +1,36 @@
+// keyboard navigation and preloading for the single image viewer
+(() => {
+  const viewer = document.querySelector(".viewer");
+
+  if (!viewer) {
+    return;
+  }
+
+  const keys = {
+    ArrowLeft: "prev",
+    ArrowRight: "next",
+    Home: "first",
+    End: "last",
+    Escape: "up",
+  };
+
+  document.addEventListener("keydown", (event) => {
+    if (event.altKey || event.ctrlKey || event.metaKey) {
+      return;
+    }
+
+    const href = viewer.dataset[keys[event.key]];
+
+    if (href) {
+      event.preventDefault();
+      window.location.href = href;
+    }
+  });
+
+  // warm the cache so stepping through a folder doesnt wait on every image
+  for (const src of [viewer.dataset.nextSrc, viewer.dataset.prevSrc]) {
+    if (src) {
+      new Image().src = src;
+    }
+  }
+})();
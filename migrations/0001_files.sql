@@ -0,0 +1,31 @@
+-- every file and directory under the served root, paths are relative to the root
+CREATE TABLE files (
+    path TEXT PRIMARY KEY NOT NULL,
+    parent TEXT NOT NULL,
+    name TEXT NOT NULL,
+    is_dir INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    -- nanoseconds since the epoch
+    mtime INTEGER NOT NULL,
+    ctime INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    dev INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    width INTEGER,
+    height INTEGER,
+    -- blake3, hex encoded
+    hash TEXT,
+    indexed_at INTEGER NOT NULL
+);
+
+CREATE INDEX files_parent ON files (parent);
+CREATE INDEX files_identity ON files (dev, inode);
+CREATE INDEX files_hash ON files (hash);
+
+-- directories that have been scanned, used to tell if a listing can be served from the index
+CREATE TABLE dirs (
+    path TEXT PRIMARY KEY NOT NULL,
+    mtime INTEGER NOT NULL,
+    scanned_at INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- tags belong to a file's identity rather than its path, so they follow it through renames and moves
+CREATE TABLE tags (
+    dev INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    tag TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (dev, inode, tag)
+);
+
+CREATE INDEX tags_tag ON tags (tag);
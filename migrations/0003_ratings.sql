@@ -0,0 +1,12 @@
+-- culling state, keyed by identity like tags
+CREATE TABLE ratings (
+    dev INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    -- 0 to 5 stars, 0 is unrated
+    rating INTEGER NOT NULL DEFAULT 0,
+    favourite INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (dev, inode)
+);
+
+CREATE INDEX ratings_favourite ON ratings (favourite);
@@ -0,0 +1,17 @@
+CREATE TABLE albums (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL
+);
+
+-- files are kept by identity like tags, position orders them within the album
+CREATE TABLE album_entries (
+    album INTEGER NOT NULL REFERENCES albums (id) ON DELETE CASCADE,
+    dev INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (album, dev, inode)
+);
+
+CREATE INDEX album_entries_position ON album_entries (album, position);
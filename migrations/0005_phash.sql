@@ -0,0 +1,5 @@
+-- 64 bit difference hash of images, stored as a signed integer
+ALTER TABLE files ADD COLUMN phash INTEGER;
+
+-- which version of the per file details (dimensions, hashes..) a row has, older rows get redone
+ALTER TABLE files ADD COLUMN details_version INTEGER NOT NULL DEFAULT 0;
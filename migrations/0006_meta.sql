@@ -0,0 +1,2 @@
+-- embedded exif/xmp/iptc metadata of images, as json (see meta::Meta)
+ALTER TABLE files ADD COLUMN meta TEXT;
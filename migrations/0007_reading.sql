@@ -0,0 +1,11 @@
+-- where the reader left off in a dir of pages, keyed by path since dirs inside archives
+-- dont have an identity of their own
+CREATE TABLE reading (
+    path TEXT PRIMARY KEY,
+    -- file name of the last page looked at
+    page TEXT,
+    -- single, spread or scroll (see reader::ReadMode)
+    mode TEXT NOT NULL DEFAULT 'single',
+    rtl INTEGER NOT NULL DEFAULT 0,
+    read_at INTEGER NOT NULL
+);
@@ -0,0 +1,9 @@
+-- when a file was created in nanoseconds since the epoch, 0 where the filesystem doesnt say.
+-- inodes get reused once a file is deleted, this tells the new file apart from the old one so it
+-- doesnt inherit its tags and the like
+ALTER TABLE files ADD COLUMN btime INTEGER NOT NULL DEFAULT 0;
+
+-- the birth time of the file each row was made for
+ALTER TABLE tags ADD COLUMN btime INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE ratings ADD COLUMN btime INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE album_entries ADD COLUMN btime INTEGER NOT NULL DEFAULT 0;
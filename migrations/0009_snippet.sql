@@ -0,0 +1,2 @@
+-- the first few lines of text files, shown on their card (see text::snippet)
+ALTER TABLE files ADD COLUMN snippet TEXT;
@@ -0,0 +1,466 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, Metadata},
+    io::{BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream};
+use tokio::sync::mpsc;
+use zip::ZipArchive;
+
+use crate::{
+    db::{Error, FileRecord},
+    partials::FileType,
+};
+
+// cbz is a zip of comic pages, nothing more
+const EXTENSIONS: [&str; 2] = ["zip", "cbz"];
+
+// how much of a member is read at a time when streaming it out
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// biggest member read into memory, a tiny zip can claim (or inflate to) gigabytes
+const MAX_READ_BYTES: u64 = 256 * 1024 * 1024;
+
+type Zip = ZipArchive<BufReader<File>>;
+
+// A file or dir inside an archive
+struct Entry {
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    // nanoseconds since the epoch, zips dont say which zone so its taken as utc
+    mtime: Option<i64>,
+}
+
+// by name only, for paths already known to be files
+pub fn is_archive_name(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    is_archive_name(path) && path.is_file()
+}
+
+// Splits a path into the archive it goes through and the path inside that, which is empty
+// for the archive itself. paths inside are plain names only, anything like .. makes it no path
+pub fn split(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let archive = path.ancestors().find(|ancestor| is_archive(ancestor))?;
+    let inner = path.strip_prefix(archive).ok()?;
+
+    if !plain(inner) {
+        return None;
+    }
+
+    Some((archive.to_path_buf(), inner.to_path_buf()))
+}
+
+// Like split, but only for things inside an archive and not the archive itself
+pub fn member(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    split(path).filter(|(_, inner)| !inner.as_os_str().is_empty())
+}
+
+fn plain(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn open(archive: &Path) -> Result<Zip, Error> {
+    Ok(ZipArchive::new(BufReader::new(File::open(archive)?))?)
+}
+
+fn entries(zip: &mut Zip) -> Vec<Entry> {
+    (0..zip.len())
+        .filter_map(|index| {
+            let file = zip.by_index_raw(index).ok()?;
+
+            // links could point anywhere, and encrypted files cant be read anyway
+            if file.is_symlink() || file.encrypted() {
+                return None;
+            }
+
+            // enclosed_name tidies up names like foo/../bar or /bar, but then the name shown
+            // wouldnt be the one find looks up, so only names that are plain already count
+            let path = file.enclosed_name().filter(|path| named(&file, path))?;
+
+            if path.as_os_str().is_empty() {
+                return None;
+            }
+
+            Some(Entry {
+                path,
+                is_dir: file.is_dir(),
+                size: file.size(),
+                mtime: file.last_modified().and_then(nanos),
+            })
+        })
+        .collect()
+}
+
+// Whether path is the plain name the file is stored under, and not just what it boils down to
+fn named<R: Read>(file: &zip::read::ZipFile<R>, path: &Path) -> bool {
+    plain(path)
+        && file
+            .name()
+            .is_ok_and(|name| Path::new(name.as_ref()) == path)
+}
+
+fn nanos(time: zip::DateTime) -> Option<i64> {
+    chrono::NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)?
+        .and_hms_opt(
+            time.hour() as u32,
+            time.minute() as u32,
+            time.second() as u32,
+        )?
+        .and_utc()
+        .timestamp_nanos_opt()
+}
+
+// Index of the file at inner, looked up by name rather than by going through every entry
+fn find(zip: &mut Zip, inner: &Path) -> Option<usize> {
+    let index = zip.index_for_name(inner.to_str()?)?;
+    let file = zip.by_index_raw(index).ok()?;
+
+    // the same ones entries leaves out
+    if file.is_dir() || file.is_symlink() || file.encrypted() || !named(&file, inner) {
+        return None;
+    }
+
+    Some(index)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+}
+
+// What the path is inside its archive, the archive itself counts as a dir, None if its not in
+// there. this opens the archive, so look it up once rather than asking again for every check
+pub fn kind(path: &Path) -> Option<Kind> {
+    let (archive, inner) = split(path)?;
+
+    if inner.as_os_str().is_empty() {
+        return Some(Kind::Dir);
+    }
+
+    let mut zip = open(&archive).ok()?;
+
+    if find(&mut zip, &inner).is_some() {
+        return Some(Kind::File);
+    }
+
+    // dirs often have no entry of their own, theyre only there in the paths below them
+    let prefix = format!("{}/", inner.to_str()?);
+
+    let is_dir = zip
+        .file_names()
+        .flatten()
+        .any(|name| name.starts_with(&prefix) && plain(Path::new(name.as_ref())));
+
+    is_dir.then_some(Kind::Dir)
+}
+
+// Whether the path is an archive, or a dir inside one
+pub fn is_dir(path: &Path) -> bool {
+    kind(path) == Some(Kind::Dir)
+}
+
+// metadata of the file, or of the archive its in, so cached copies of members go stale with it
+pub fn metadata(path: &Path) -> std::io::Result<Metadata> {
+    path.metadata().or_else(|err| match member(path) {
+        Some((archive, _)) => archive.metadata(),
+        None => Err(err),
+    })
+}
+
+fn record(path: PathBuf, is_dir: bool, size: u64, mtime: i64) -> FileRecord {
+    FileRecord {
+        file_type: if is_dir {
+            FileType::Dir
+        } else {
+            FileType::from(&path)
+        },
+        path,
+        is_dir,
+        size,
+        mtime,
+        ctime: mtime,
+        btime: 0,
+        inode: 0,
+        dev: 0,
+        width: None,
+        height: None,
+        hash: None,
+        phash: None,
+        meta: None,
+        snippet: None,
+        tags: vec![],
+        rating: 0,
+        favourite: false,
+        albums: vec![],
+        archived: true,
+    }
+}
+
+// What a dir inside an archive (or the archive itself) contains, as records whose paths go
+// through the archive. recursive lists every file below it instead, like a flattened listing
+pub fn list(dir: &Path, recursive: bool) -> Result<Vec<FileRecord>, Error> {
+    let (archive, inner) = split(dir).ok_or("not an archive")?;
+
+    let meta = archive.metadata()?;
+    let archive_mtime = FileRecord::from_fs(archive.clone(), &meta).mtime;
+
+    let mut records = BTreeMap::new();
+
+    for entry in entries(&mut open(&archive)?) {
+        let Ok(rest) = entry.path.strip_prefix(&inner) else {
+            continue;
+        };
+
+        let mtime = entry.mtime.unwrap_or(archive_mtime);
+
+        if recursive {
+            if !entry.is_dir {
+                records.insert(
+                    rest.to_path_buf(),
+                    record(dir.join(rest), false, entry.size, mtime),
+                );
+            }
+
+            continue;
+        }
+
+        let mut components = rest.components();
+
+        // the dir itself
+        let Some(first) = components.next() else {
+            continue;
+        };
+
+        let is_dir = entry.is_dir || components.next().is_some();
+
+        records
+            .entry(PathBuf::from(first.as_os_str()))
+            .or_insert_with(|| {
+                record(
+                    dir.join(first),
+                    is_dir,
+                    if is_dir { 0 } else { entry.size },
+                    mtime,
+                )
+            });
+    }
+
+    Ok(records.into_values().collect())
+}
+
+// Reads a whole member into memory, as long as its no bigger than MAX_READ_BYTES
+pub fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let (archive, inner) = member(path).ok_or("not in an archive")?;
+
+    let mut zip = open(&archive)?;
+    let index = find(&mut zip, &inner).ok_or("no such file in the archive")?;
+
+    let file = zip.by_index(index)?;
+
+    if file.size() > MAX_READ_BYTES {
+        return Err("file in the archive is too big to read".into());
+    }
+
+    // the declared size can lie, so dont trust it for more than the check above
+    let mut buf = vec![];
+    file.take(MAX_READ_BYTES).read_to_end(&mut buf)?;
+
+    Ok(buf)
+}
+
+// Reads no more than the first limit bytes of a member, for when the rest isnt needed
+pub fn read_head(path: &Path, limit: u64) -> Result<Vec<u8>, Error> {
+    let (archive, inner) = member(path).ok_or("not in an archive")?;
+
+    let mut zip = open(&archive)?;
+    let index = find(&mut zip, &inner).ok_or("no such file in the archive")?;
+
+    let mut buf = vec![];
+    zip.by_index(index)?.take(limit).read_to_end(&mut buf)?;
+
+    Ok(buf)
+}
+
+// Streams a member straight out of its archive, along with how big it is going to be
+pub fn stream(
+    path: &Path,
+) -> Result<(u64, impl Stream<Item = Result<Bytes, actix_web::Error>>), Error> {
+    let (archive, inner) = member(path).ok_or("not in an archive")?;
+
+    let mut zip = open(&archive)?;
+    let index = find(&mut zip, &inner).ok_or("no such file in the archive")?;
+    let size = zip.by_index_raw(index)?.size();
+
+    // the zip reader blocks, so it gets a thread of its own and hands chunks over
+    let (tx, rx) = mpsc::channel(4);
+
+    actix_web::rt::task::spawn_blocking(move || {
+        let mut file = match zip.by_index(index) {
+            Ok(file) => file,
+            Err(err) => {
+                let _ = tx.blocking_send(Err(std::io::Error::other(err)));
+                return;
+            }
+        };
+
+        loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+
+            let chunk = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => {
+                    buf.truncate(read);
+                    Ok(Bytes::from(buf))
+                }
+                Err(err) => Err(err),
+            };
+
+            let failed = chunk.is_err();
+
+            // an error on sending means the client went away
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok((
+        size,
+        stream::unfold(rx, |mut rx| async move {
+            let chunk = rx.recv().await?;
+            Some((
+                chunk.map_err(actix_web::error::ErrorInternalServerError),
+                rx,
+            ))
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    // a fresh dir with a.zip in it, holding the given files
+    fn archive(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("iv-archive-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut zip = ZipWriter::new(File::create(dir.join("a.zip")).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+        for (name, contents) in files {
+            if name.ends_with('/') {
+                zip.add_directory(*name, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(contents).unwrap();
+            }
+        }
+
+        zip.finish().unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn split_paths() {
+        let dir = archive("split", &[]);
+        let zip = dir.join("a.zip");
+
+        assert_eq!(split(&zip), Some((zip.clone(), PathBuf::new())));
+        assert_eq!(
+            split(&zip.join("x/y.png")),
+            Some((zip.clone(), PathBuf::from("x/y.png")))
+        );
+        assert_eq!(member(&zip), None);
+
+        // climbing out of the archive, even if it lands back in it
+        assert_eq!(split(&zip.join("../a.zip")), None);
+        assert_eq!(split(&zip.join("foo/../bar")), None);
+        // a . in the middle is dropped by Path itself, its the same path either way
+        assert_eq!(
+            split(&zip.join("./bar")),
+            Some((zip.clone(), PathBuf::from("bar")))
+        );
+
+        // an archive inside an archive isnt opened, its just a path in the outer one
+        assert_eq!(
+            split(&zip.join("b.zip/c.png")),
+            Some((zip.clone(), PathBuf::from("b.zip/c.png")))
+        );
+
+        // nothing to split when theres no archive
+        assert_eq!(split(&dir.join("c.png")), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn plain_paths() {
+        assert!(plain(Path::new("a/b.png")));
+        assert!(plain(Path::new("")));
+        assert!(!plain(Path::new("/etc/passwd")));
+        assert!(!plain(Path::new("../b.png")));
+        assert!(!plain(Path::new("a/../b.png")));
+        assert!(!plain(Path::new("./b.png")));
+    }
+
+    #[test]
+    fn lookups() {
+        let dir = archive(
+            "lookups",
+            &[
+                ("pages/1.png", b"one"),
+                ("empty/", b""),
+                ("foo/../bar.png", b"sneaky"),
+                ("/abs.png", b"absolute"),
+            ],
+        );
+        let zip = dir.join("a.zip");
+
+        assert_eq!(kind(&zip), Some(Kind::Dir));
+        assert_eq!(kind(&zip.join("pages")), Some(Kind::Dir));
+        assert_eq!(kind(&zip.join("pages/1.png")), Some(Kind::File));
+        assert_eq!(kind(&zip.join("empty")), Some(Kind::Dir));
+        assert_eq!(kind(&zip.join("pages/2.png")), None);
+        assert_eq!(kind(&zip.join("page")), None);
+
+        // entries that would escape or double up never show up
+        assert_eq!(kind(&zip.join("bar.png")), None);
+        assert_eq!(kind(&zip.join("foo")), None);
+        assert_eq!(kind(&zip.join("abs.png")), None);
+
+        let names: Vec<PathBuf> = list(&zip, true)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.path)
+            .collect();
+        assert_eq!(names, vec![zip.join("pages/1.png")]);
+
+        assert_eq!(read(&zip.join("pages/1.png")).unwrap(), b"one");
+        assert!(read(&zip.join("pages")).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
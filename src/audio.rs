@@ -0,0 +1,83 @@
+use std::{
+    io::{Cursor, Read, Seek},
+    path::Path,
+};
+
+use image::DynamicImage;
+use lofty::{file::TaggedFile, picture::PictureType, prelude::*, probe::Probe};
+
+use crate::{archive, db::Error, meta::Meta};
+
+fn probe(path: &Path) -> Result<TaggedFile, Error> {
+    // members of archives are only ever read into memory
+    if archive::member(path).is_some() {
+        return read(Cursor::new(archive::read(path)?));
+    }
+
+    Ok(Probe::open(path)?.guess_file_type()?.read()?)
+}
+
+fn read<R: Read + Seek>(reader: R) -> Result<TaggedFile, Error> {
+    Ok(Probe::new(reader).guess_file_type()?.read()?)
+}
+
+// What the id3, vorbis comment or mp4 tags of a file say, plus how long it plays
+pub fn meta(path: &Path) -> Option<Meta> {
+    let file = probe(path).ok()?;
+    let tag = file.primary_tag().or_else(|| file.first_tag());
+
+    let text = |value: Option<std::borrow::Cow<str>>| {
+        value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let duration = file.properties().duration().as_secs_f64();
+
+    let meta = Meta {
+        title: tag.and_then(|tag| text(tag.title())),
+        artist: tag.and_then(|tag| text(tag.artist())),
+        album: tag.and_then(|tag| text(tag.album())),
+        duration: (duration > 0.0).then_some(duration),
+        cover: tag.is_some_and(|tag| !tag.pictures().is_empty()),
+        ..Default::default()
+    };
+
+    (meta != Meta::default()).then_some(meta)
+}
+
+// The embedded cover art, the front cover if theres more than one picture.
+// This decodes the whole picture, so call it from a blocking context
+pub fn cover(path: &Path) -> Result<DynamicImage, Error> {
+    let file = probe(path)?;
+
+    let pictures = file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .collect::<Vec<_>>();
+
+    let picture = pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or(pictures.first())
+        .ok_or("no cover art")?;
+
+    Ok(image::load_from_memory(picture.data())?)
+}
+
+// m:ss, or h:mm:ss for anything an hour or longer
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
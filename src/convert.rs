@@ -0,0 +1,188 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use image::{
+    metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbaImage,
+};
+use jxl_oxide::integration::JxlDecoder;
+
+use crate::{
+    archive, cache_root,
+    db::{Error, FileRecord},
+    partials::url_path,
+    raw, sniff, thumbs, Args,
+};
+
+// What browsers show in an <img> by themselves, anything else gets converted on the server.
+// avif is left to the browser, the image crate can only decode it with dav1d linked in, so
+// there would be nothing to fall back to for the few browsers without it anyway
+const BROWSER_NATIVE: [&str; 10] = [
+    "image/apng",
+    "image/avif",
+    "image/bmp",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/svg+xml",
+    "image/vnd.microsoft.icon",
+    "image/webp",
+    "image/x-icon",
+];
+
+pub fn browser_native(mime: &str) -> bool {
+    BROWSER_NATIVE.contains(&mime)
+}
+
+// url the browser should load an image from, the original if it can show it by itself
+pub fn image_url(record: &FileRecord) -> String {
+    if browser_native(record.file_type.mime()) {
+        format!("/!_/{}", url_path(&record.path))
+    } else {
+        format!("/!convert/{}?v={:x}", url_path(&record.path), record.mtime)
+    }
+}
+
+pub fn cache_dir(args: &Args) -> PathBuf {
+    cache_root(args).join("converted")
+}
+
+// Teaches the image crate formats it cant decode by itself, call once at startup
+pub fn register_decoders() {
+    // libheif applies the rotation and mirroring stored in the container itself
+    #[cfg(feature = "heif")]
+    libheif_rs::integration::image::register_all_decoding_hooks();
+}
+
+// Decodes an image the right way up, phones store most portraits sideways with an exif
+// orientation tag saying how to turn them. This decodes the whole image, so call it from
+// a blocking context
+pub fn decode(path: &Path) -> Result<DynamicImage, Error> {
+    let file_type = sniff::file_type(path);
+
+    // members of archives are only ever read into memory
+    if archive::member(path).is_some() {
+        let bytes = archive::read(path)?;
+
+        return match file_type.mime() {
+            mime if raw::is_raw(mime) => Err("raws inside archives arent supported".into()),
+            "image/jxl" => decode_jxl(Cursor::new(bytes)),
+            "image/vnd.adobe.photoshop" => decode_psd(&bytes),
+            _ => decode_oriented(ImageReader::new(Cursor::new(bytes)).with_guessed_format()?),
+        };
+    }
+
+    match file_type.mime() {
+        // the jpeg preview in the raw, actually developing it is well beyond an image viewer
+        mime if raw::is_raw(mime) => raw::Preview::read(path)?.decode(),
+        "image/jxl" => decode_jxl(BufReader::new(File::open(path)?)),
+        "image/vnd.adobe.photoshop" => decode_psd(&std::fs::read(path)?),
+        _ => decode_oriented(ImageReader::open(path)?.with_guessed_format()?),
+    }
+}
+
+fn decode_oriented<R: BufRead + Seek>(reader: ImageReader<R>) -> Result<DynamicImage, Error> {
+    let mut decoder = reader.into_decoder()?;
+
+    // a broken exif block shouldnt stop the image itself from showing up
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+
+    Ok(img)
+}
+
+// Width and height the image is shown at, from its headers alone. the same sides as decode
+// gives, so portraits stored sideways dont report the wrong way round
+pub fn dimensions(path: &Path) -> Result<(u32, u32), Error> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+
+    let (width, height) = decoder.dimensions();
+
+    // these turn the image a quarter, so the sides swap
+    let turned = matches!(
+        decoder.orientation(),
+        Ok(Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH)
+    );
+
+    Ok(if turned {
+        (height, width)
+    } else {
+        (width, height)
+    })
+}
+
+// jxl turns itself the right way up, the exif orientation in it is only informational
+fn decode_jxl(reader: impl Read) -> Result<DynamicImage, Error> {
+    Ok(DynamicImage::from_decoder(JxlDecoder::new(reader)?)?)
+}
+
+// the flattened copy photoshop saves next to the layers, so layers dont matter here
+fn decode_psd(bytes: &[u8]) -> Result<DynamicImage, Error> {
+    let psd = psd::Psd::from_bytes(bytes)?;
+
+    let img = RgbaImage::from_raw(psd.width(), psd.height(), psd.rgba())
+        .ok_or("psd image data doesnt match its dimensions")?;
+
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+// Returns the path to the cached browser friendly copy of an image, converting it if needed.
+// This decodes the whole image, so call it from a blocking context
+pub fn get_or_create(args: &Args, path: &Path) -> Result<PathBuf, Error> {
+    let meta = archive::metadata(path)?;
+    let is_raw = raw::is_raw(sniff::file_type(path).mime());
+
+    // raw previews are jpegs already, so they stay jpegs
+    let out = thumbs::cached_path(&cache_dir(args), path, &meta, "full")
+        .with_extension(if is_raw { "jpg" } else { "webp" });
+
+    if out.exists() {
+        log::trace!("converted cache hit: {:?} -> {:?}", path, out);
+        return Ok(out);
+    }
+
+    std::fs::create_dir_all(out.parent().unwrap())?;
+
+    // written to a temp file first, so a half written copy never gets served
+    let tmp = thumbs::temp_path(&out);
+
+    if is_raw {
+        let jpeg = raw::Preview::read(path)?.browser_jpeg()?;
+        std::fs::write(&tmp, jpeg).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+        std::fs::rename(&tmp, &out)?;
+
+        log::debug!("extracted raw preview: {:?} -> {:?}", path, out);
+
+        return Ok(out);
+    }
+
+    // the webp encoder only takes 8 bit rgb(a), deeper images get flattened
+    let img = decode(path)?;
+    let img = if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+
+    // lossless, so the copy looks exactly like the original would
+    img.save_with_format(&tmp, ImageFormat::WebP)
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+    std::fs::rename(&tmp, &out)?;
+
+    log::debug!("converted image: {:?} -> {:?}", path, out);
+
+    Ok(out)
+}
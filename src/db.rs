@@ -0,0 +1,1119 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::Metadata,
+    os::{linux::fs::MetadataExt, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use actix_rt::{Arbiter, ArbiterHandle};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow},
+    ConnectOptions, Row, SqlitePool,
+};
+
+use crate::{
+    audio, cache_root, convert, fnv1a64,
+    meta::Meta,
+    partials::FileType,
+    reader::{ReadMode, Reading},
+    similar, sniff, text, Args, PWD,
+};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// how many changed files get hashed/measured before theyre written to the index
+const REFRESH_BATCH: usize = 64;
+
+// bump when compute_details learns something new, so already indexed files get redone
+const DETAILS_VERSION: i64 = 8;
+
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    // nanoseconds since the epoch
+    pub mtime: i64,
+    pub ctime: i64,
+    // when the file was created, 0 if the filesystem doesnt keep that
+    pub btime: i64,
+    pub inode: u64,
+    pub dev: u64,
+    pub file_type: FileType,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub hash: Option<String>,
+    // perceptual hash of images, see similar::dhash
+    pub phash: Option<u64>,
+    pub meta: Option<Meta>,
+    // first lines of text files for their card, read while indexing so rendering doesnt
+    pub snippet: Option<String>,
+    // not part of the files table, filled in by Db::annotate
+    pub tags: Vec<String>,
+    // 0 to 5 stars, 0 is unrated
+    pub rating: u8,
+    pub favourite: bool,
+    pub albums: Vec<String>,
+    // lives inside a zip, so theres no inode to hang tags and the like on
+    pub archived: bool,
+}
+
+impl FileRecord {
+    pub fn from_fs(path: PathBuf, meta: &Metadata) -> Self {
+        let file_type = if meta.is_dir() {
+            FileType::Dir
+        } else {
+            FileType::from(&path)
+        };
+
+        FileRecord {
+            path,
+            is_dir: meta.is_dir(),
+            size: meta.st_size(),
+            mtime: meta.st_mtime() * 1_000_000_000 + meta.st_mtime_nsec(),
+            ctime: meta.st_ctime() * 1_000_000_000 + meta.st_ctime_nsec(),
+            btime: meta
+                .created()
+                .ok()
+                .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |created| created.as_nanos() as i64),
+            inode: meta.st_ino(),
+            dev: meta.st_dev(),
+            file_type,
+            width: None,
+            height: None,
+            hash: None,
+            phash: None,
+            meta: None,
+            snippet: None,
+            tags: vec![],
+            rating: 0,
+            favourite: false,
+            albums: vec![],
+            archived: false,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn from_row(row: &SqliteRow) -> Self {
+        let path: String = row.get("path");
+        let kind: String = row.get("kind");
+        let mime: String = row.get("mime");
+
+        FileRecord {
+            path: PWD.read().unwrap().join(path),
+            is_dir: row.get("is_dir"),
+            size: row.get::<i64, _>("size") as u64,
+            mtime: row.get("mtime"),
+            ctime: row.get("ctime"),
+            btime: row.get("btime"),
+            inode: row.get::<i64, _>("inode") as u64,
+            dev: row.get::<i64, _>("dev") as u64,
+            file_type: FileType::from_kind(&kind, &mime),
+            width: row.get::<Option<i64>, _>("width").map(|w| w as u32),
+            height: row.get::<Option<i64>, _>("height").map(|h| h as u32),
+            hash: row.get("hash"),
+            phash: row.get::<Option<i64>, _>("phash").map(|phash| phash as u64),
+            meta: row
+                .get::<Option<String>, _>("meta")
+                .and_then(|meta| serde_json::from_str(&meta).ok()),
+            snippet: row.get("snippet"),
+            tags: vec![],
+            rating: 0,
+            favourite: false,
+            albums: vec![],
+            archived: false,
+        }
+    }
+
+    // the expensive bits, only done for new or changed files
+    pub fn compute_details(&mut self) {
+        if self.is_dir {
+            return;
+        }
+
+        // the extension was only a first guess, this looks at whats actually in the file
+        self.file_type = sniff::file_type(&self.path);
+
+        if matches!(self.file_type, FileType::Audio(_)) {
+            self.meta = audio::meta(&self.path);
+        }
+
+        if text::is_text(self.file_type.mime()) {
+            self.snippet = text::snippet(self);
+        }
+
+        if matches!(self.file_type, FileType::Image(_)) {
+            self.meta = Meta::read(&self.path);
+
+            match convert::decode(&self.path) {
+                Ok(img) => {
+                    self.width = Some(img.width());
+                    self.height = Some(img.height());
+                    self.phash = Some(similar::dhash(&img));
+                }
+                // formats the decoder doesnt know might still have a readable header
+                Err(_) => {
+                    if let Ok((width, height)) = convert::dimensions(&self.path) {
+                        self.width = Some(width);
+                        self.height = Some(height);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// how many identities annotate looks up per statement, sqlite caps the parameters in one
+const IDENTITY_CHUNK: usize = 400;
+
+// `(dev, inode) IN (...)` with a pair of parameters for each of count identities
+fn identity_filter(count: usize) -> String {
+    format!(
+        "(dev, inode) IN (VALUES {})",
+        vec!["(?, ?)"; count].join(", ")
+    )
+}
+
+fn bind_identities<'q>(
+    query: sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    identities: &[(u64, u64)],
+) -> sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>> {
+    identities.iter().fold(query, |query, (dev, inode)| {
+        query.bind(*dev as i64).bind(*inode as i64)
+    })
+}
+
+fn identity(row: &SqliteRow) -> (u64, u64) {
+    (
+        row.get::<i64, _>("dev") as u64,
+        row.get::<i64, _>("inode") as u64,
+    )
+}
+
+// tables that hang things off a files identity rather than its path
+const IDENTITY_TABLES: [&str; 3] = ["tags", "ratings", "album_entries"];
+
+// Whether a row made for a file born at `owner` belongs to a file born at `btime`. a different
+// birth time means the inode was reused after the file the row was made for got deleted, 0 is a
+// filesystem that doesnt keep birth times (or a row from before they were kept)
+fn same_birth(owner: i64, btime: i64) -> bool {
+    owner == 0 || btime == 0 || owner == btime
+}
+
+// Drops whatever a deleted file that had this files inode left behind, so it isnt inherited.
+// rows from before birth times were kept go to whoever has the identity now
+async fn claim_identity(
+    conn: &mut sqlx::SqliteConnection,
+    record: &FileRecord,
+) -> Result<(), sqlx::Error> {
+    if record.btime == 0 {
+        return Ok(());
+    }
+
+    for table in IDENTITY_TABLES {
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE dev = ?1 AND inode = ?2 AND btime != 0 AND btime != ?3",
+            table
+        ))
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(record.btime)
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(&format!(
+            "UPDATE {} SET btime = ?3 WHERE dev = ?1 AND inode = ?2 AND btime = 0",
+            table
+        ))
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(record.btime)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// path relative to the served root, which is what the index stores
+pub fn rel_path(path: &Path) -> String {
+    path.strip_prefix(PWD.read().unwrap().as_path())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+fn dir_mtime(dir: &Path) -> Option<i64> {
+    dir.metadata()
+        .ok()
+        .map(|meta| meta.st_mtime() * 1_000_000_000 + meta.st_mtime_nsec())
+}
+
+fn scan_dir(dir: &Path) -> std::io::Result<Vec<FileRecord>> {
+    Ok(dir
+        .read_dir()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            let meta = path.metadata().ok()?;
+            Some(FileRecord::from_fs(path, &meta))
+        })
+        .collect())
+}
+
+// Persistent per-root metadata index
+#[derive(Clone)]
+pub struct Db {
+    pool: SqlitePool,
+    // refreshes run on their own arbiter, so they can finish cleanly when the server shuts down
+    arbiter: ArbiterHandle,
+    // directories with a refresh in flight, so visiting a page twice doesnt scan twice
+    refreshing: Arc<Mutex<HashSet<PathBuf>>>,
+    // whether the duplicate hashing job is running
+    hashing: Arc<AtomicBool>,
+}
+
+impl Db {
+    pub async fn open(args: &Args, root: &Path) -> Result<Self, sqlx::Error> {
+        let dir = cache_root(args).join("index");
+        std::fs::create_dir_all(&dir)?;
+
+        // one database per served root
+        let file = dir.join(format!(
+            "{:0>16x}.sqlite",
+            fnv1a64(root.as_os_str().as_bytes())
+        ));
+
+        let options = SqliteConnectOptions::new()
+            .filename(&file)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .log_statements(log::LevelFilter::Trace);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+
+        sqlx::migrate!().run(&pool).await?;
+
+        log::info!("Using index: {}", file.to_string_lossy());
+
+        Ok(Db {
+            pool,
+            arbiter: Arbiter::new().handle(),
+            refreshing: Default::default(),
+            hashing: Default::default(),
+        })
+    }
+
+    // Waits for in flight queries, then stops the background arbiter
+    pub async fn close(&self) {
+        self.pool.close().await;
+        self.arbiter.stop();
+    }
+
+    // Returns the indexed listing of a directory, if the index is up to date with it
+    pub async fn list_dir(&self, dir: &Path) -> Option<Vec<FileRecord>> {
+        let parent = rel_path(dir);
+        let mtime = dir_mtime(dir)?;
+
+        let scanned: Option<i64> = sqlx::query_scalar("SELECT mtime FROM dirs WHERE path = ?")
+            .bind(&parent)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("index lookup failed: {}", err);
+                None
+            });
+
+        if scanned != Some(mtime) {
+            return None;
+        }
+
+        match sqlx::query("SELECT * FROM files WHERE parent = ?")
+            .bind(&parent)
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(rows) => Some(rows.iter().map(FileRecord::from_row).collect()),
+            Err(err) => {
+                log::warn!("index lookup failed: {}", err);
+                None
+            }
+        }
+    }
+
+    // Every entry under dir, up to max_depth directories below it (0 is just dir itself).
+    // Uses the index for directories it has up to date and the filesystem for the rest,
+    // without queueing refreshes, since that would hash the whole tree
+    pub async fn walk(&self, dir: &Path, max_depth: usize) -> Vec<FileRecord> {
+        let root = PWD.read().unwrap().clone();
+
+        let mut results = vec![];
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([(dir.to_path_buf(), 0)]);
+
+        while let Some((dir, depth)) = queue.pop_front() {
+            let entries = match self.list_dir(&dir).await {
+                Some(entries) => entries,
+                None => actix_web::web::block(move || scan_dir(&dir))
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .unwrap_or_default(),
+            };
+
+            for record in entries {
+                // symlinks can loop, or point out of the served root
+                if record.is_dir
+                    && depth < max_depth
+                    && visited.insert((record.dev, record.inode))
+                    && record
+                        .path
+                        .canonicalize()
+                        .is_ok_and(|path| path.starts_with(&root))
+                {
+                    queue.push_back((record.path.clone(), depth + 1));
+                }
+
+                results.push(record);
+            }
+        }
+
+        results
+    }
+
+    // Fills in the tags, ratings, favourites and albums of every record
+    pub async fn annotate(&self, mut records: Vec<FileRecord>) -> Vec<FileRecord> {
+        let identities: Vec<(u64, u64)> = records
+            .iter()
+            .filter(|record| !record.archived)
+            .map(|record| (record.dev, record.inode))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut tags: HashMap<(u64, u64), Vec<(i64, String)>> = HashMap::new();
+        let mut ratings: HashMap<(u64, u64), (i64, u8, bool)> = HashMap::new();
+        let mut albums: HashMap<(u64, u64), Vec<(i64, String)>> = HashMap::new();
+
+        for chunk in identities.chunks(IDENTITY_CHUNK) {
+            let filter = identity_filter(chunk.len());
+
+            let sql = format!(
+                "SELECT dev, inode, btime, tag FROM tags WHERE {} ORDER BY tag",
+                filter
+            );
+
+            match bind_identities(sqlx::query(&sql), chunk)
+                .fetch_all(&self.pool)
+                .await
+            {
+                Ok(rows) => {
+                    for row in rows {
+                        tags.entry(identity(&row))
+                            .or_default()
+                            .push((row.get("btime"), row.get("tag")));
+                    }
+                }
+                Err(err) => log::warn!("tag lookup failed: {}", err),
+            }
+
+            let sql = format!(
+                "SELECT dev, inode, btime, rating, favourite FROM ratings WHERE {}",
+                filter
+            );
+
+            match bind_identities(sqlx::query(&sql), chunk)
+                .fetch_all(&self.pool)
+                .await
+            {
+                Ok(rows) => {
+                    for row in rows {
+                        ratings.insert(
+                            identity(&row),
+                            (
+                                row.get("btime"),
+                                row.get::<i64, _>("rating") as u8,
+                                row.get("favourite"),
+                            ),
+                        );
+                    }
+                }
+                Err(err) => log::warn!("rating lookup failed: {}", err),
+            }
+
+            let sql = format!(
+                "SELECT dev, inode, btime, albums.name FROM album_entries \
+                JOIN albums ON albums.id = album_entries.album WHERE {} ORDER BY albums.name",
+                filter
+            );
+
+            match bind_identities(sqlx::query(&sql), chunk)
+                .fetch_all(&self.pool)
+                .await
+            {
+                Ok(rows) => {
+                    for row in rows {
+                        albums
+                            .entry(identity(&row))
+                            .or_default()
+                            .push((row.get("btime"), row.get("name")));
+                    }
+                }
+                Err(err) => log::warn!("album lookup failed: {}", err),
+            }
+        }
+
+        for record in records.iter_mut() {
+            let identity = (record.dev, record.inode);
+
+            // rows left by a deleted file whose inode this one got dont count, see same_birth
+            let owned = |rows: Option<&Vec<(i64, String)>>| {
+                rows.into_iter()
+                    .flatten()
+                    .filter(|(btime, _)| same_birth(*btime, record.btime))
+                    .map(|(_, name)| name.clone())
+                    .collect()
+            };
+
+            record.tags = owned(tags.get(&identity));
+            record.albums = owned(albums.get(&identity));
+
+            if let Some((_, rating, favourite)) = ratings
+                .get(&identity)
+                .filter(|(btime, _, _)| same_birth(*btime, record.btime))
+            {
+                record.rating = *rating;
+                record.favourite = *favourite;
+            }
+        }
+
+        records
+    }
+
+    // Sets whichever of the rating and favourite flag is given, leaving the other as it was
+    pub async fn rate(
+        &self,
+        record: &FileRecord,
+        rating: Option<u8>,
+        favourite: Option<bool>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        claim_identity(&mut tx, record).await?;
+
+        sqlx::query(
+            "INSERT INTO ratings (dev, inode, rating, favourite, updated_at, btime) \
+            VALUES (?1, ?2, coalesce(?3, 0), coalesce(?4, 0), ?5, ?6) \
+            ON CONFLICT(dev, inode) DO UPDATE SET \
+            rating = coalesce(?3, rating), favourite = coalesce(?4, favourite), updated_at = ?5",
+        )
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(rating.map(|rating| rating as i64))
+        .bind(favourite)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(record.btime)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    // Where the reader was left in a dir, if it was ever opened there
+    pub async fn reading(&self, dir: &Path) -> Option<Reading> {
+        let row = sqlx::query("SELECT page, mode, rtl FROM reading WHERE path = ?")
+            .bind(rel_path(dir))
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("reading lookup failed: {}", err);
+                None
+            })?;
+
+        Some(Reading {
+            page: row.get("page"),
+            mode: ReadMode::parse(row.get("mode")).unwrap_or_default(),
+            rtl: row.get("rtl"),
+        })
+    }
+
+    pub async fn save_reading(&self, dir: &Path, reading: &Reading) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO reading (path, page, mode, rtl, read_at) VALUES (?1, ?2, ?3, ?4, ?5) \
+            ON CONFLICT(path) DO UPDATE SET page = ?2, mode = ?3, rtl = ?4, read_at = ?5",
+        )
+        .bind(rel_path(dir))
+        .bind(&reading.page)
+        .bind(reading.mode.as_str())
+        .bind(reading.rtl)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Appends a file to an album, creating the album if needed. files already in it stay put
+    pub async fn add_to_album(&self, album: &str, record: &FileRecord) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+
+        claim_identity(&mut tx, record).await?;
+
+        sqlx::query("INSERT OR IGNORE INTO albums (name, created_at) VALUES (?, ?)")
+            .bind(album)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            "INSERT OR IGNORE INTO album_entries (album, dev, inode, position, added_at, btime) \
+            SELECT id, ?2, ?3, \
+            (SELECT coalesce(max(position), 0) + 1 FROM album_entries WHERE album = albums.id), ?4, \
+            ?5 FROM albums WHERE name = ?1",
+        )
+        .bind(album)
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(now)
+        .bind(record.btime)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn remove_from_album(
+        &self,
+        album: &str,
+        record: &FileRecord,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM album_entries WHERE dev = ? AND inode = ? \
+            AND album = (SELECT id FROM albums WHERE name = ?)",
+        )
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(album)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Every album, with how many files are in it
+    pub async fn all_albums(&self) -> Result<Vec<(String, i64)>, sqlx::Error> {
+        Ok(sqlx::query(
+            "SELECT name, count(album_entries.album) AS count FROM albums \
+            LEFT JOIN album_entries ON album_entries.album = albums.id \
+            GROUP BY albums.id ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| (row.get("name"), row.get("count")))
+        .collect())
+    }
+
+    pub async fn delete_album(&self, album: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM albums WHERE name = ?")
+            .bind(album)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // The files of an album in album order
+    pub async fn album(&self, album: &str) -> Vec<FileRecord> {
+        self.by_identity(
+            sqlx::query(
+                "SELECT files.* FROM album_entries \
+                JOIN albums ON albums.id = album_entries.album \
+                JOIN files USING (dev, inode) \
+                WHERE albums.name = ? ORDER BY album_entries.position",
+            )
+            .bind(album),
+        )
+        .await
+    }
+
+    pub async fn favourites(&self) -> Vec<FileRecord> {
+        self.by_identity(sqlx::query(
+            "SELECT files.* FROM ratings JOIN files USING (dev, inode) WHERE ratings.favourite = 1",
+        ))
+        .await
+    }
+
+    pub async fn add_tag(&self, record: &FileRecord, tag: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        claim_identity(&mut tx, record).await?;
+
+        sqlx::query(
+            "INSERT OR IGNORE INTO tags (dev, inode, tag, created_at, btime) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(record.dev as i64)
+        .bind(record.inode as i64)
+        .bind(tag)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(record.btime)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn remove_tag(&self, record: &FileRecord, tag: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM tags WHERE dev = ? AND inode = ? AND tag = ?")
+            .bind(record.dev as i64)
+            .bind(record.inode as i64)
+            .bind(tag)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Every tag in use, with how many files have it
+    pub async fn all_tags(&self) -> Result<Vec<(String, i64)>, sqlx::Error> {
+        Ok(
+            sqlx::query("SELECT tag, count(*) AS count FROM tags GROUP BY tag ORDER BY tag")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|row| (row.get("tag"), row.get("count")))
+                .collect(),
+        )
+    }
+
+    // Renames a tag everywhere, merging it into `to` if that already exists
+    pub async fn rename_tag(&self, from: &str, to: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT OR IGNORE INTO tags (dev, inode, tag, created_at, btime) \
+            SELECT dev, inode, ?, created_at, btime FROM tags WHERE tag = ?",
+        )
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM tags WHERE tag = ? AND tag != ?")
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn delete_tag(&self, tag: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM tags WHERE tag = ?")
+            .bind(tag)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn tagged(&self, tag: &str) -> Vec<FileRecord> {
+        self.by_identity(
+            sqlx::query(
+                "SELECT files.* FROM tags JOIN files USING (dev, inode) WHERE tags.tag = ?",
+            )
+            .bind(tag),
+        )
+        .await
+    }
+
+    // Runs a query for files found through their identity, and keeps the ones that are still
+    // where the index thinks they are. files that moved into a directory that hasnt been
+    // indexed yet show up once it is
+    async fn by_identity<'q>(
+        &self,
+        query: sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    ) -> Vec<FileRecord> {
+        let rows = query.fetch_all(&self.pool).await.unwrap_or_else(|err| {
+            log::warn!("index lookup failed: {}", err);
+            vec![]
+        });
+
+        rows.iter()
+            .map(FileRecord::from_row)
+            .filter(|record| {
+                // the old path of a moved file stays in the index until its directory is rescanned
+                record
+                    .path
+                    .metadata()
+                    .is_ok_and(|meta| meta.st_ino() == record.inode && meta.st_dev() == record.dev)
+            })
+            .collect()
+    }
+
+    // The indexed record of a file with all its details, if the index is up to date with it
+    pub async fn detailed(&self, record: &FileRecord) -> Option<FileRecord> {
+        sqlx::query(
+            "SELECT * FROM files WHERE path = ? AND size = ? AND mtime = ? AND details_version = ?",
+        )
+        .bind(rel_path(&record.path))
+        .bind(record.size as i64)
+        .bind(record.mtime)
+        .bind(DETAILS_VERSION)
+        .fetch_optional(&self.pool)
+        .await
+        .ok()?
+        .as_ref()
+        .map(FileRecord::from_row)
+    }
+
+    // Every indexed image with a perceptual hash
+    pub async fn hashed_images(&self) -> Vec<FileRecord> {
+        sqlx::query("SELECT * FROM files WHERE phash IS NOT NULL")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("index lookup failed: {}", err);
+                vec![]
+            })
+            .iter()
+            .map(FileRecord::from_row)
+            .collect()
+    }
+
+    // Queues a refresh for every directory under dir the index isnt up to date with, for
+    // reports that need the whole tree. returns how many that was
+    pub async fn index_tree(&self, dir: &Path, max_depth: usize) -> usize {
+        let dirs = std::iter::once(dir.to_path_buf()).chain(
+            self.walk(dir, max_depth.saturating_sub(1))
+                .await
+                .into_iter()
+                .filter(|record| record.is_dir)
+                .map(|record| record.path),
+        );
+
+        let mut queued = 0;
+
+        for dir in dirs {
+            if self.list_dir(&dir).await.is_none() {
+                self.refresh_in_background(dir);
+                queued += 1;
+            }
+        }
+
+        queued
+    }
+
+    // Starts hashing every file that could have a byte identical twin, unless thats already going
+    pub fn hash_in_background(&self) {
+        if self.hashing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let db = self.clone();
+
+        self.arbiter.spawn(async move {
+            if let Err(err) = db.hash_candidates().await {
+                log::warn!("failed to hash duplicate candidates: {}", err);
+            }
+
+            db.hashing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    pub fn is_hashing(&self) -> bool {
+        self.hashing.load(Ordering::SeqCst)
+    }
+
+    // only files that share their size with another file can be duplicates,
+    // which is usually a tiny fraction of them, so thats all that gets hashed
+    const CANDIDATES: &'static str = "FROM files WHERE is_dir = 0 AND size > 0 AND hash IS NULL \
+        AND size IN (SELECT size FROM files WHERE is_dir = 0 GROUP BY size HAVING count(*) > 1)";
+
+    // How many files still need hashing before the duplicates report is complete
+    pub async fn unhashed_candidates(&self) -> i64 {
+        sqlx::query_scalar(&format!("SELECT count(*) {}", Self::CANDIDATES))
+            .fetch_one(&self.pool)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("index lookup failed: {}", err);
+                0
+            })
+    }
+
+    async fn hash_candidates(&self) -> Result<(), Error> {
+        let candidates: Vec<FileRecord> = sqlx::query(&format!("SELECT * {}", Self::CANDIDATES))
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(FileRecord::from_row)
+            .collect();
+
+        log::debug!("hashing {} duplicate candidates", candidates.len());
+
+        for batch in candidates.chunks(REFRESH_BATCH) {
+            let hashed = actix_web::web::block({
+                let batch = batch.to_vec();
+                move || {
+                    batch
+                        .into_iter()
+                        .filter_map(|record| {
+                            // files that changed since they were indexed get hashed on the next refresh
+                            let meta = record.path.metadata().ok()?;
+                            let current = FileRecord::from_fs(record.path.clone(), &meta);
+
+                            if (current.size, current.mtime) != (record.size, record.mtime) {
+                                return None;
+                            }
+
+                            Some((record, hash_file(&current.path).ok()?))
+                        })
+                        .collect::<Vec<_>>()
+                }
+            })
+            .await?;
+
+            let mut tx = self.pool.begin().await?;
+
+            for (record, hash) in hashed {
+                sqlx::query("UPDATE files SET hash = ? WHERE path = ? AND mtime = ?")
+                    .bind(hash)
+                    .bind(rel_path(&record.path))
+                    .bind(record.mtime)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            tx.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    // Indexed files that share their content hash with another, grouped, biggest files first
+    pub async fn identical_files(&self) -> Vec<Vec<FileRecord>> {
+        let rows = sqlx::query(
+            "SELECT * FROM files WHERE hash IN \
+            (SELECT hash FROM files WHERE hash IS NOT NULL GROUP BY hash HAVING count(*) > 1) \
+            ORDER BY size DESC, hash, path",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_else(|err| {
+            log::warn!("index lookup failed: {}", err);
+            vec![]
+        });
+
+        let mut groups: Vec<Vec<FileRecord>> = vec![];
+
+        for record in rows.iter().map(FileRecord::from_row) {
+            match groups.last_mut() {
+                Some(group) if group[0].hash == record.hash => group.push(record),
+                _ => groups.push(vec![record]),
+            }
+        }
+
+        groups
+    }
+
+    pub fn refresh_in_background(&self, dir: PathBuf) {
+        let db = self.clone();
+
+        self.arbiter.spawn(async move {
+            if let Err(err) = db.refresh_dir(dir.clone()).await {
+                log::warn!("failed to refresh index for {:?}: {}", dir, err);
+            }
+        });
+    }
+
+    pub async fn refresh_dir(&self, dir: PathBuf) -> Result<(), Error> {
+        if !self.refreshing.lock().unwrap().insert(dir.clone()) {
+            return Ok(());
+        }
+
+        let result = self.scan(&dir).await;
+
+        self.refreshing.lock().unwrap().remove(&dir);
+
+        result
+    }
+
+    async fn scan(&self, dir: &Path) -> Result<(), Error> {
+        let parent = rel_path(dir);
+
+        // grab this before reading the dir, so a change during the scan makes the next visit rescan
+        let Some(mtime) = dir_mtime(dir) else {
+            return Ok(());
+        };
+
+        let records = actix_web::web::block({
+            let dir = dir.to_path_buf();
+            move || scan_dir(&dir)
+        })
+        .await??;
+
+        let existing: HashMap<String, (i64, i64, i64, i64)> = sqlx::query(
+            "SELECT path, size, mtime, inode, details_version FROM files WHERE parent = ?",
+        )
+        .bind(&parent)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| {
+            (
+                row.get("path"),
+                (
+                    row.get("size"),
+                    row.get("mtime"),
+                    row.get("inode"),
+                    row.get("details_version"),
+                ),
+            )
+        })
+        .collect();
+
+        let seen: HashSet<String> = records
+            .iter()
+            .map(|record| rel_path(&record.path))
+            .collect();
+
+        let changed: Vec<FileRecord> = records
+            .into_iter()
+            .filter(|record| {
+                existing.get(&rel_path(&record.path))
+                    != Some(&(
+                        record.size as i64,
+                        record.mtime,
+                        record.inode as i64,
+                        DETAILS_VERSION,
+                    ))
+            })
+            .collect();
+
+        log::debug!(
+            "refreshing index for {:?}: {} changed, {} total",
+            dir,
+            changed.len(),
+            seen.len()
+        );
+
+        for batch in changed.chunks(REFRESH_BATCH) {
+            let batch = actix_web::web::block({
+                let mut batch = batch.to_vec();
+                move || {
+                    batch.iter_mut().for_each(FileRecord::compute_details);
+                    batch
+                }
+            })
+            .await?;
+
+            self.upsert(&batch).await?;
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        for path in existing.keys().filter(|path| !seen.contains(*path)) {
+            // removes the entry and, if it was a directory, everything under it
+            for table in ["files", "dirs"] {
+                sqlx::query(&format!(
+                    "DELETE FROM {} WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+                    table
+                ))
+                .bind(path)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        sqlx::query(
+            "INSERT INTO dirs (path, mtime, scanned_at) VALUES (?, ?, ?) \
+            ON CONFLICT(path) DO UPDATE SET mtime = excluded.mtime, scanned_at = excluded.scanned_at",
+        )
+        .bind(&parent)
+        .bind(mtime)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    async fn upsert(&self, records: &[FileRecord]) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        let mut tx = self.pool.begin().await?;
+
+        for record in records {
+            let path = rel_path(&record.path);
+            let parent = record.path.parent().map(rel_path).unwrap_or_default();
+
+            sqlx::query(
+                "INSERT INTO files \
+                (path, parent, name, is_dir, size, mtime, ctime, btime, inode, dev, kind, mime, width, \
+                height, hash, phash, meta, snippet, details_version, indexed_at) \
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+                ON CONFLICT(path) DO UPDATE SET \
+                parent = excluded.parent, name = excluded.name, is_dir = excluded.is_dir, \
+                size = excluded.size, mtime = excluded.mtime, ctime = excluded.ctime, btime = excluded.btime, \
+                inode = excluded.inode, dev = excluded.dev, kind = excluded.kind, mime = excluded.mime, \
+                width = excluded.width, height = excluded.height, hash = excluded.hash, \
+                phash = excluded.phash, meta = excluded.meta, snippet = excluded.snippet, details_version = excluded.details_version, \
+                indexed_at = excluded.indexed_at",
+            )
+            .bind(path)
+            .bind(parent)
+            .bind(record.name())
+            .bind(record.is_dir)
+            .bind(record.size as i64)
+            .bind(record.mtime)
+            .bind(record.ctime)
+            .bind(record.btime)
+            .bind(record.inode as i64)
+            .bind(record.dev as i64)
+            .bind(record.file_type.kind())
+            .bind(record.file_type.mime())
+            .bind(record.width)
+            .bind(record.height)
+            .bind(&record.hash)
+            .bind(record.phash.map(|phash| phash as i64))
+            .bind(
+                record
+                    .meta
+                    .as_ref()
+                    .and_then(|meta| serde_json::to_string(meta).ok()),
+            )
+            .bind(&record.snippet)
+            .bind(DETAILS_VERSION)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+
+            claim_identity(&mut tx, record).await?;
+        }
+
+        tx.commit().await
+    }
+}
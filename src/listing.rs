@@ -0,0 +1,718 @@
+use std::{cmp::Ordering, path::Path};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use unicase::UniCase;
+
+use crate::{
+    db::{rel_path, FileRecord},
+    partials::FileType,
+    search::SearchMode,
+    Args,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Name,
+    Mtime,
+    Ctime,
+    Size,
+    Type,
+    Dimensions,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 6] = [
+        SortKey::Name,
+        SortKey::Mtime,
+        SortKey::Ctime,
+        SortKey::Size,
+        SortKey::Type,
+        SortKey::Dimensions,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Mtime => "mtime",
+            SortKey::Ctime => "ctime",
+            SortKey::Size => "size",
+            SortKey::Type => "type",
+            SortKey::Dimensions => "dimensions",
+        }
+    }
+
+    // newest/biggest first is what you want for anything thats not text
+    pub fn default_order(&self) -> SortOrder {
+        match self {
+            SortKey::Name | SortKey::Type => SortOrder::Asc,
+            SortKey::Mtime | SortKey::Ctime | SortKey::Size | SortKey::Dimensions => {
+                SortOrder::Desc
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+
+    pub fn reversed(&self) -> Self {
+        match self {
+            SortOrder::Asc => SortOrder::Desc,
+            SortOrder::Desc => SortOrder::Asc,
+        }
+    }
+}
+
+// How file names are compared
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Collation {
+    // digit runs compare as numbers, so frame_2 comes before frame_10
+    Natural,
+    // the old ascii lowercased order
+    Bytes,
+}
+
+impl Collation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Collation::Natural => "natural",
+            Collation::Bytes => "bytes",
+        }
+    }
+
+    pub fn toggled(&self) -> Self {
+        match self {
+            Collation::Natural => Collation::Bytes,
+            Collation::Bytes => Collation::Natural,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    // (digits without leading zeros, digits, leading zeros), numbers sort before text
+    Number(usize, String, usize),
+    Text(UniCase<String>),
+}
+
+// Splits a name into alternating text and number chunks, for natural ordering
+fn natural_key(name: &str) -> Vec<NameChunk> {
+    let mut chunks = vec![];
+    let mut rest = name;
+
+    while let Some(first) = rest.chars().next() {
+        let is_digit = first.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+
+        let (chunk, tail) = rest.split_at(end);
+
+        if is_digit {
+            let digits = chunk.trim_start_matches('0');
+            chunks.push(NameChunk::Number(
+                digits.len(),
+                digits.to_string(),
+                chunk.len() - digits.len(),
+            ));
+        } else {
+            chunks.push(NameChunk::Text(UniCase::new(chunk.to_string())));
+        }
+
+        rest = tail;
+    }
+
+    chunks
+}
+
+// Groups of file types that can be toggled on and off in a listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Show {
+    Dirs,
+    Images,
+    Videos,
+    Audio,
+    Other,
+}
+
+impl Show {
+    pub const ALL: [Show; 5] = [
+        Show::Dirs,
+        Show::Images,
+        Show::Videos,
+        Show::Audio,
+        Show::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Show::Dirs => "dirs",
+            Show::Images => "images",
+            Show::Videos => "videos",
+            Show::Audio => "audio",
+            Show::Other => "other",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Show::ALL.into_iter().find(|show| show.as_str() == s.trim())
+    }
+
+    fn matches(&self, file_type: &FileType) -> bool {
+        matches!(
+            (self, file_type),
+            (Show::Dirs, FileType::Dir)
+                | (Show::Images, FileType::Image(_))
+                | (Show::Videos, FileType::Video(_))
+                | (Show::Audio, FileType::Audio(_))
+                | (Show::Other, FileType::Unknown(_))
+        )
+    }
+}
+
+// Query parameters shared by every page that shows a listing
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListingQuery {
+    pub sort: Option<SortKey>,
+    pub order: Option<SortOrder>,
+    pub collation: Option<Collation>,
+    // comma separated list of Show's, everything if missing
+    pub show: Option<String>,
+    // comma separated globs matched against file names, directories are never hidden by it
+    pub glob: Option<String>,
+    // hides files rated lower, directories are never hidden by it
+    pub min_rating: Option<u8>,
+    // only used by /!search
+    pub q: Option<String>,
+    pub mode: Option<SearchMode>,
+    // flattens the tree below the listing, "1" or "0", --flatten decides if missing
+    pub recursive: Option<String>,
+    // how far below the listing a recursive listing goes, capped by --depth
+    pub depth: Option<usize>,
+    // the dir a flattened listing is of, relative to the root. set on the links out of one so
+    // the viewer steps through the same files as the grid did
+    pub tree: Option<String>,
+    // only used by /!duplicates and /!similar, how many hash bits apart still counts as alike
+    pub distance: Option<u32>,
+    // 1 based
+    pub page: Option<usize>,
+}
+
+// Set by the grid script when it wants the next page as a json fragment instead of a whole page
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FragmentQuery {
+    #[serde(default)]
+    pub fragment: bool,
+}
+
+pub struct Page {
+    // 1 based
+    pub number: usize,
+    pub count: usize,
+}
+
+impl Page {
+    pub fn next(&self) -> Option<usize> {
+        (self.number < self.count).then_some(self.number + 1)
+    }
+
+    pub fn prev(&self) -> Option<usize> {
+        (self.number > 1).then_some(self.number - 1)
+    }
+}
+
+// Cuts the window for the requested page out of a full listing
+pub fn paginate<T>(entries: Vec<T>, query: &ListingQuery, page_size: usize) -> (Vec<T>, Page) {
+    let page_size = page_size.max(1);
+    let count = entries.len().div_ceil(page_size).max(1);
+    let number = query.page.unwrap_or(1).clamp(1, count);
+
+    let entries = entries
+        .into_iter()
+        .skip((number - 1) * page_size)
+        .take(page_size)
+        .collect();
+
+    (entries, Page { number, count })
+}
+
+impl ListingQuery {
+    pub fn sort_key(&self, args: &Args) -> SortKey {
+        self.sort.unwrap_or(args.sort)
+    }
+
+    pub fn sort_order(&self, args: &Args) -> SortOrder {
+        self.order
+            .unwrap_or_else(|| self.sort_key(args).default_order())
+    }
+
+    pub fn collation(&self, args: &Args) -> Collation {
+        self.collation.unwrap_or(args.collation)
+    }
+
+    pub fn recursive(&self, args: &Args) -> bool {
+        match self.recursive.as_deref() {
+            Some(recursive) => matches!(recursive, "1" | "true" | "yes" | "on"),
+            None => args.flatten,
+        }
+    }
+
+    pub fn depth(&self, args: &Args) -> usize {
+        self.depth.unwrap_or(args.depth).min(args.depth)
+    }
+
+    pub fn shown(&self) -> Vec<Show> {
+        match &self.show {
+            Some(show) => show.split(',').filter_map(Show::parse).collect(),
+            None => Show::ALL.to_vec(),
+        }
+    }
+
+    fn glob_set(&self) -> Option<GlobSet> {
+        let globs = self.glob.as_ref().filter(|glob| !glob.trim().is_empty())?;
+
+        let mut builder = GlobSetBuilder::new();
+
+        for glob in globs
+            .split(',')
+            .map(str::trim)
+            .filter(|glob| !glob.is_empty())
+        {
+            match GlobBuilder::new(glob).case_insensitive(true).build() {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(err) => log::debug!("ignoring invalid glob {:?}: {}", glob, err),
+            }
+        }
+
+        builder.build().ok()
+    }
+
+    pub fn min_rating(&self) -> u8 {
+        self.min_rating.unwrap_or(0).min(5)
+    }
+
+    // Drops everything the show/glob/min_rating parameters filter out
+    pub fn filter_entries(&self, entries: Vec<FileRecord>) -> Vec<FileRecord> {
+        let shown = self.shown();
+        let glob_set = self.glob_set();
+        let min_rating = self.min_rating();
+
+        entries
+            .into_iter()
+            .filter(|record| shown.iter().any(|show| show.matches(&record.file_type)))
+            .filter(|record| {
+                record.is_dir
+                    || glob_set
+                        .as_ref()
+                        .is_none_or(|glob_set| glob_set.is_match(record.name()))
+            })
+            .filter(|record| record.is_dir || record.rating >= min_rating)
+            .collect()
+    }
+
+    pub fn pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![];
+
+        if let Some(sort) = self.sort {
+            pairs.push(("sort", sort.as_str().to_string()));
+        }
+
+        if let Some(order) = self.order {
+            pairs.push(("order", order.as_str().to_string()));
+        }
+
+        if let Some(collation) = self.collation {
+            pairs.push(("collation", collation.as_str().to_string()));
+        }
+
+        if let Some(show) = &self.show {
+            pairs.push(("show", show.clone()));
+        }
+
+        if let Some(glob) = self.glob.as_ref().filter(|glob| !glob.is_empty()) {
+            pairs.push(("glob", glob.clone()));
+        }
+
+        if let Some(min_rating) = self.min_rating.filter(|min_rating| *min_rating > 0) {
+            pairs.push(("min_rating", min_rating.to_string()));
+        }
+
+        if let Some(q) = &self.q {
+            pairs.push(("q", q.clone()));
+        }
+
+        if let Some(mode) = self.mode {
+            pairs.push(("mode", mode.as_str().to_string()));
+        }
+
+        if let Some(recursive) = &self.recursive {
+            pairs.push(("recursive", recursive.clone()));
+        }
+
+        if let Some(depth) = self.depth {
+            pairs.push(("depth", depth.to_string()));
+        }
+
+        if let Some(tree) = &self.tree {
+            pairs.push(("tree", tree.clone()));
+        }
+
+        if let Some(distance) = self.distance {
+            pairs.push(("distance", distance.to_string()));
+        }
+
+        if let Some(page) = self.page.filter(|page| *page > 1) {
+            pairs.push(("page", page.to_string()));
+        }
+
+        pairs
+    }
+
+    // Query string to append to links so the listing state carries over, empty if theres nothing to keep
+    pub fn query_string(&self) -> String {
+        let pairs = self.pairs();
+
+        if pairs.is_empty() {
+            return String::new();
+        }
+
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+            .collect::<Vec<_>>();
+
+        format!("?{}", pairs.join("&"))
+    }
+
+    // The same listing state, for links that leave the search page
+    pub fn without_search(&self) -> Self {
+        let mut query = self.first_page();
+        query.q = None;
+        query.mode = None;
+        query
+    }
+
+    // The same listing state without the page, for links that change what the listing contains
+    pub fn first_page(&self) -> Self {
+        let mut query = self.clone();
+        query.page = None;
+        query
+    }
+
+    pub fn with_page(&self, page: usize) -> Self {
+        let mut query = self.clone();
+        query.page = Some(page);
+        query
+    }
+
+    pub fn with_sort(&self, sort: SortKey, order: SortOrder) -> Self {
+        let mut query = self.first_page();
+        query.sort = Some(sort);
+        query.order = Some(order);
+        query
+    }
+
+    pub fn with_collation(&self, collation: Collation) -> Self {
+        let mut query = self.first_page();
+        query.collation = Some(collation);
+        query
+    }
+
+    pub fn with_tree(&self, dir: &Path) -> Self {
+        let mut query = self.clone();
+        query.tree = Some(rel_path(dir));
+        query
+    }
+
+    pub fn with_recursive(&self, recursive: bool) -> Self {
+        let mut query = self.first_page();
+        query.recursive = Some(if recursive { "1" } else { "0" }.to_string());
+        query
+    }
+
+    pub fn with_distance(&self, distance: u32) -> Self {
+        let mut query = self.first_page();
+        query.distance = Some(distance);
+        query
+    }
+
+    pub fn with_min_rating(&self, min_rating: u8) -> Self {
+        let mut query = self.first_page();
+        query.min_rating = Some(min_rating);
+        query
+    }
+
+    pub fn with_show_toggled(&self, toggled: Show) -> Self {
+        let mut shown = self.shown();
+
+        if shown.contains(&toggled) {
+            shown.retain(|show| *show != toggled);
+        } else {
+            shown.push(toggled);
+        }
+
+        let mut query = self.first_page();
+
+        // keep urls short when everything is visible anyway
+        query.show = if Show::ALL.iter().all(|show| shown.contains(show)) {
+            None
+        } else {
+            Some(
+                Show::ALL
+                    .iter()
+                    .filter(|show| shown.contains(show))
+                    .map(Show::as_str)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+        };
+
+        query
+    }
+}
+
+fn compare(a: &FileRecord, b: &FileRecord, key: SortKey) -> Ordering {
+    match key {
+        // names are handled by sort_entries itself
+        SortKey::Name => Ordering::Equal,
+        SortKey::Mtime => a.mtime.cmp(&b.mtime),
+        SortKey::Ctime => a.ctime.cmp(&b.ctime),
+        SortKey::Size => a.size.cmp(&b.size),
+        SortKey::Type => a
+            .file_type
+            .kind()
+            .cmp(b.file_type.kind())
+            .then_with(|| a.file_type.mime().cmp(b.file_type.mime())),
+        SortKey::Dimensions => {
+            let pixels = |record: &FileRecord| {
+                record
+                    .width
+                    .zip(record.height)
+                    .map(|(width, height)| width as u64 * height as u64)
+            };
+
+            pixels(a).cmp(&pixels(b))
+        }
+    }
+}
+
+// Sorts a listing, directories always come first, ties are broken by name
+pub fn sort_entries(
+    entries: &mut [FileRecord],
+    key: SortKey,
+    order: SortOrder,
+    collation: Collation,
+) {
+    match collation {
+        Collation::Natural => entries.sort_by_cached_key(|record| {
+            let name = record.name();
+            // the raw name keeps the order stable for names that only differ in case/zeros
+            (natural_key(&name), name)
+        }),
+        Collation::Bytes => entries.sort_by_cached_key(|record| record.name().to_ascii_lowercase()),
+    }
+
+    if key == SortKey::Name {
+        if order == SortOrder::Desc {
+            entries.reverse();
+        }
+    } else {
+        // stable, so names stay ascending among equal keys
+        entries.sort_by(|a, b| match order {
+            SortOrder::Asc => compare(a, b, key),
+            SortOrder::Desc => compare(a, b, key).reverse(),
+        });
+    }
+
+    entries.sort_by_key(|record| !record.is_dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn record(name: &str, is_dir: bool, size: u64) -> FileRecord {
+        let path = PathBuf::from("/srv").join(name);
+
+        FileRecord {
+            file_type: if is_dir {
+                FileType::Dir
+            } else {
+                FileType::from(&path)
+            },
+            path,
+            is_dir,
+            size,
+            mtime: 0,
+            ctime: 0,
+            btime: 0,
+            inode: 0,
+            dev: 0,
+            width: None,
+            height: None,
+            hash: None,
+            phash: None,
+            meta: None,
+            snippet: None,
+            tags: vec![],
+            rating: 0,
+            favourite: false,
+            albums: vec![],
+            archived: false,
+        }
+    }
+
+    fn sorted(names: &[&str], key: SortKey, order: SortOrder, collation: Collation) -> Vec<String> {
+        let mut entries: Vec<FileRecord> =
+            names.iter().map(|name| record(name, false, 0)).collect();
+
+        sort_entries(&mut entries, key, order, collation);
+
+        entries.iter().map(FileRecord::name).collect()
+    }
+
+    fn natural(names: &[&str]) -> Vec<String> {
+        sorted(names, SortKey::Name, SortOrder::Asc, Collation::Natural)
+    }
+
+    #[test]
+    fn numbers_by_value() {
+        assert_eq!(
+            natural(&["frame_10.png", "frame_2.png", "frame_1.png"]),
+            ["frame_1.png", "frame_2.png", "frame_10.png"]
+        );
+        assert!(natural_key("frame_2") < natural_key("frame_10"));
+        assert!(natural_key("v1.9.2") < natural_key("v1.10.0"));
+    }
+
+    #[test]
+    fn leading_zeros() {
+        // the same number, fewer zeros first
+        assert!(natural_key("1") < natural_key("01"));
+        assert!(natural_key("01") < natural_key("001"));
+        // but the value still decides before the zeros do
+        assert!(natural_key("002") < natural_key("10"));
+        assert_eq!(
+            natural(&["page_010", "page_9", "page_01", "page_1"]),
+            ["page_1", "page_01", "page_9", "page_010"]
+        );
+    }
+
+    #[test]
+    fn long_digit_runs() {
+        // far past what fits in a u64
+        let big = "99999999999999999999999999999";
+        let bigger = "100000000000000000000000000000";
+
+        assert!(natural_key(big) < natural_key(bigger));
+        assert!(natural_key(&format!("{}0", big)) > natural_key(bigger));
+        assert_eq!(
+            natural(&[
+                &format!("scan_{}", bigger),
+                &format!("scan_{}", big),
+                "scan_7"
+            ]),
+            [
+                "scan_7".to_string(),
+                format!("scan_{}", big),
+                format!("scan_{}", bigger)
+            ]
+        );
+    }
+
+    #[test]
+    fn case_folding() {
+        assert_eq!(natural_key("Straße"), natural_key("STRASSE"));
+        assert_eq!(natural_key("ÉCLAIR"), natural_key("éclair"));
+        assert_eq!(
+            natural(&["banana", "Apple", "cherry"]),
+            ["Apple", "banana", "cherry"]
+        );
+        // names that fold the same keep a fixed order between them
+        assert_eq!(
+            natural(&["éclair", "Éclair"]),
+            natural(&["Éclair", "éclair"])
+        );
+    }
+
+    #[test]
+    fn bytes_collation() {
+        assert_eq!(
+            sorted(
+                &["frame_2", "frame_10", "Frame_3"],
+                SortKey::Name,
+                SortOrder::Asc,
+                Collation::Bytes
+            ),
+            ["frame_10", "frame_2", "Frame_3"]
+        );
+    }
+
+    #[test]
+    fn reverse_keeps_dirs_first() {
+        let mut entries = vec![
+            record("b.png", false, 30),
+            record("photos", true, 0),
+            record("a.png", false, 10),
+            record("c.png", false, 10),
+            record("albums", true, 0),
+        ];
+
+        let names = |entries: &[FileRecord]| -> Vec<String> {
+            entries.iter().map(FileRecord::name).collect()
+        };
+
+        sort_entries(
+            &mut entries,
+            SortKey::Name,
+            SortOrder::Desc,
+            Collation::Natural,
+        );
+        assert_eq!(
+            names(&entries),
+            ["photos", "albums", "c.png", "b.png", "a.png"]
+        );
+
+        // ties on the key stay in name order, whichever way the key goes
+        sort_entries(
+            &mut entries,
+            SortKey::Size,
+            SortOrder::Desc,
+            Collation::Natural,
+        );
+        assert_eq!(
+            names(&entries),
+            ["albums", "photos", "b.png", "a.png", "c.png"]
+        );
+
+        sort_entries(
+            &mut entries,
+            SortKey::Size,
+            SortOrder::Asc,
+            Collation::Natural,
+        );
+        assert_eq!(
+            names(&entries),
+            ["albums", "photos", "a.png", "c.png", "b.png"]
+        );
+    }
+}
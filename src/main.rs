@@ -1,21 +1,43 @@
 use std::{
     env,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionType};
 use actix_web::{
+    dev::Service,
+    http::{header, Method},
     web::{self, Data},
     App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 
 use clap::Parser;
+use db::{Db, FileRecord};
 use fern::colors::{Color, ColoredLevelConfig};
-use partials::FooterArgs;
-
+use futures_util::future::{ready, Either};
+use listing::{Collation, FragmentQuery, ListingQuery, Page, SortKey};
+use partials::{FileType, FooterArgs};
+use serde::{Deserialize, Serialize};
+
+mod archive;
+mod audio;
+mod convert;
+mod db;
+mod listing;
+mod markdown;
+mod meta;
 mod partials;
+mod raw;
+mod reader;
+mod search;
+mod similar;
+mod sniff;
+mod text;
+mod thumbs;
+mod watch;
 
 lazy_static::lazy_static! {
     static ref PWD: Arc<RwLock<PathBuf>> = Arc::new(RwLock::new(env::current_dir().unwrap()));
@@ -48,6 +70,59 @@ pub struct Args {
         help = "Allow directory traversal"
     )]
     traverse: bool,
+
+    #[clap(long, help = "Cache directory, defaults to $XDG_CACHE_HOME/iv")]
+    cache_dir: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = SortKey::Name, help = "Default sort order of listings")]
+    sort: SortKey,
+
+    #[clap(long, value_enum, default_value_t = Collation::Natural, help = "How file names are ordered")]
+    collation: Collation,
+
+    #[clap(long, default_value_t = 200, help = "Entries per page of a listing")]
+    page_size: usize,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Flatten listings to every file below them by default"
+    )]
+    flatten: bool,
+
+    #[clap(long, default_value_t = 32, help = "How deep flattened listings go")]
+    depth: usize,
+
+    #[clap(
+        long,
+        default_value_t = 8,
+        help = "How many of the 64 perceptual hash bits may differ for images to count as similar"
+    )]
+    similarity: u32,
+}
+
+pub fn cache_root(args: &Args) -> PathBuf {
+    if let Some(dir) = &args.cache_dir {
+        return dir.clone();
+    }
+
+    let base = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from(env::var("HOME").unwrap_or("/tmp".to_string())).join(".cache")
+        });
+
+    base.join("iv")
+}
+
+// not cryptographic, just stable across runs (unlike the std hasher) for naming cache files
+pub fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 pub fn setup_logging(loglevel: log::LevelFilter, to_file: bool) -> Result<(), log::SetLoggerError> {
@@ -61,16 +136,13 @@ pub fn setup_logging(loglevel: log::LevelFilter, to_file: bool) -> Result<(), lo
     // setup a stdio logger
     let stdio_log = fern::Dispatch::new()
         .level(loglevel)
-        .format({
-            let colors = colors.clone();
-            move |out, message, record| {
-                out.finish(format_args!(
-                    "[{:^7}] [{}] {}",
-                    colors.color(record.level()),
-                    record.target(),
-                    message
-                ))
-            }
+        .format(move |out, message, record| {
+            out.finish(format_args!(
+                "[{:^7}] [{}] {}",
+                colors.color(record.level()),
+                record.target(),
+                message
+            ))
         })
         .chain(std::io::stdout());
 
@@ -100,10 +172,7 @@ pub fn setup_logging(loglevel: log::LevelFilter, to_file: bool) -> Result<(), lo
                     format!("logs/iv-{}.log", date)
                 };
 
-                let (tx, rx): (
-                    std::sync::mpsc::Sender<(String, String)>,
-                    std::sync::mpsc::Receiver<(String, String)>,
-                ) = std::sync::mpsc::channel();
+                let (tx, rx) = std::sync::mpsc::channel::<(String, String)>();
 
                 let mut open_files: std::collections::HashMap<String, std::fs::File> =
                     Default::default();
@@ -151,10 +220,10 @@ pub fn setup_logging(loglevel: log::LevelFilter, to_file: bool) -> Result<(), lo
                                 // there may be some fucked up edgecase where a thread thinks its still
                                 // yesterday and it opens the file again, but should work out next time this runs
                                 if current_filename != get_current_filename() {
-                                    open_files.remove(&current_filename).map(|file| {
+                                    if let Some(file) = open_files.remove(&current_filename) {
                                         file.sync_all().unwrap();
-                                    });
-                                    // (file handle gets dropped after .remove.map, and subsequently closed)
+                                    }
+                                    // (file handle gets dropped at the end of the if let, and subsequently closed)
 
                                     current_filename = get_current_filename();
                                 }
@@ -180,7 +249,7 @@ pub fn setup_logging(loglevel: log::LevelFilter, to_file: bool) -> Result<(), lo
     }
 }
 
-fn visit_dir(dir: &PathBuf) -> Vec<PathBuf> {
+fn visit_dir(dir: &Path) -> Vec<PathBuf> {
     dir.read_dir()
         .unwrap()
         .map(|entry| entry.unwrap().path())
@@ -196,102 +265,1422 @@ fn stat_all(dirs: Vec<PathBuf>) -> Vec<(PathBuf, std::fs::Metadata)> {
 }
 
 // Canonicalizes the path and returns it if its allowed
-fn canonicalize_path(
-    path: &PathBuf,
-    args: &Args,
-    pwd: &PathBuf,
-    allow_nondir: bool,
-) -> Option<PathBuf> {
+fn canonicalize_path(path: &Path, args: &Args, pwd: &Path, allow_nondir: bool) -> Option<PathBuf> {
     let base_dir = pwd.canonicalize().unwrap();
 
     let mut target_path = base_dir.clone();
 
     if path.is_absolute() {
-        target_path.push(path.strip_prefix("/").unwrap_or(&path));
+        target_path.push(path.strip_prefix("/").unwrap_or(path));
     } else {
         target_path.push(path);
     }
 
+    // paths into archives dont exist on disk, so the archive gets checked like any other file
+    // and the rest is looked up in it. archives count as dirs, so this needs traversal
+    let inner = if args.traverse && !target_path.exists() {
+        let (archive, inner) = archive::split(&target_path)?;
+        target_path = archive;
+        Some(inner)
+    } else {
+        None
+    };
+
     if !target_path.exists() {
         return None;
     }
 
     let mut target_path = target_path.canonicalize().unwrap_or(target_path);
 
-    if !allow_nondir && !target_path.is_dir() {
+    // looked up once, opening the archive again for every check adds up on big ones
+    let is_dir = match inner {
+        Some(inner) => {
+            target_path.push(inner);
+            archive::kind(&target_path)? == archive::Kind::Dir
+        }
+        None => target_path.is_dir() || (args.traverse && archive::is_archive(&target_path)),
+    };
+
+    if !allow_nondir && !is_dir {
         target_path.pop();
     }
 
-    if !args.traverse && target_path != base_dir {
+    // files count as part of the directory theyre in, and the parent of one always is a dir
+    let target_dir = if is_dir || !allow_nondir {
+        target_path.as_path()
+    } else {
+        target_path.parent().unwrap_or(&target_path)
+    };
+
+    if !args.traverse && target_dir != base_dir {
         return None;
     }
 
     if target_path.starts_with(base_dir) {
-        return Some(target_path);
+        Some(target_path)
     } else {
-        return None;
+        None
     }
 }
 
-async fn index(
-    req: HttpRequest,
+// The served root as shown in the breadcrumb, with $HOME shortened to ~
+fn display_root(pwd: &Path) -> String {
+    let mut pwd = pwd.to_string_lossy().to_string();
+    let home = env::var("HOME").unwrap_or("/".to_string());
+
+    if pwd.starts_with(&home) {
+        pwd = pwd.replace(&home, "~");
+    }
+
+    pwd
+}
+
+// Lists a directory in display order, from the index if its up to date
+async fn list_dir(db: &Db, dir: &Path, args: &Args, query: &ListingQuery) -> Vec<FileRecord> {
+    if !dir.is_dir() {
+        return list_archive(dir, args, query, false).await;
+    }
+
+    let entries = match db.list_dir(dir).await {
+        Some(records) => {
+            log::debug!("serving path from index: {:?}", dir);
+            records
+        }
+        None => {
+            log::debug!("serving path: {:?}", dir);
+            stat_all(visit_dir(dir))
+                .into_iter()
+                .map(|(path, meta)| FileRecord::from_fs(path, &meta))
+                .collect()
+        }
+    };
+
+    db.refresh_in_background(dir.to_path_buf());
+
+    prepare_listing(db.annotate(entries).await, args, query).await
+}
+
+// Every file in the tree below a directory, for flattened listings
+async fn list_tree(db: &Db, dir: &Path, args: &Args, query: &ListingQuery) -> Vec<FileRecord> {
+    // without traversal nothing below the root is visible, so that flattens to just the root
+    let max_depth = if args.traverse { query.depth(args) } else { 0 };
+
+    if !dir.is_dir() {
+        return list_archive(dir, args, query, true).await;
+    }
+
+    log::debug!("serving tree: {:?} ({} levels)", dir, max_depth);
+
+    let entries = db
+        .walk(dir, max_depth)
+        .await
+        .into_iter()
+        .filter(|record| !record.is_dir)
+        .collect();
+
+    db.refresh_in_background(dir.to_path_buf());
+
+    prepare_listing(db.annotate(entries).await, args, query).await
+}
+
+// Lists an archive or a dir inside one, theres nothing to index or annotate in there
+async fn list_archive(
+    dir: &Path,
+    args: &Args,
+    query: &ListingQuery,
+    recursive: bool,
+) -> Vec<FileRecord> {
+    log::debug!("serving archive: {:?}", dir);
+
+    let entries = archive::list(dir, recursive).unwrap_or_else(|err| {
+        log::warn!("failed to list {:?}: {}", dir, err);
+        vec![]
+    });
+
+    prepare_listing(entries, args, query).await
+}
+
+// Filters and sorts a listing according to the query
+async fn prepare_listing(
+    entries: Vec<FileRecord>,
+    args: &Args,
+    query: &ListingQuery,
+) -> Vec<FileRecord> {
+    let mut entries = query.filter_entries(entries);
+
+    let key = query.sort_key(args);
+
+    if key == SortKey::Dimensions {
+        fill_dimensions(&mut entries).await;
+    }
+
+    listing::sort_entries(
+        &mut entries,
+        key,
+        query.sort_order(args),
+        query.collation(args),
+    );
+
+    entries
+}
+
+// listings that didnt come from the index dont have dimensions yet. this only reads the headers,
+// but thats still a file read per image, so it happens off the worker
+async fn fill_dimensions(entries: &mut [FileRecord]) {
+    let missing: Vec<(usize, PathBuf)> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| {
+            record.width.is_none() && matches!(record.file_type, FileType::Image(_))
+        })
+        .map(|(i, record)| (i, record.path.clone()))
+        .collect();
+
+    if missing.is_empty() {
+        return;
+    }
+
+    let found = web::block(move || {
+        missing
+            .into_iter()
+            .filter_map(|(i, path)| Some((i, convert::dimensions(&path).ok()?)))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    for (i, (width, height)) in found {
+        entries[i].width = Some(width);
+        entries[i].height = Some(height);
+    }
+}
+
+// A single file, from the index if its up to date with it, without listing the dir its in.
+// members of archives come out of the archive's own listing
+async fn file_record(db: &Db, path: &Path) -> Option<FileRecord> {
+    if archive::member(path).is_some() {
+        let path = path.to_path_buf();
+
+        return web::block(move || {
+            archive::list(path.parent()?, false)
+                .ok()?
+                .into_iter()
+                .find(|record| record.path == path)
+        })
+        .await
+        .ok()
+        .flatten();
+    }
+
+    let record = FileRecord::from_fs(path.to_path_buf(), &path.metadata().ok()?);
+
+    Some(db.detailed(&record).await.unwrap_or(record))
+}
+
+// What a file is, from the index if its up to date with it. sniffing reads the start of the
+// file, which is left to the blocking pool so a slow disk doesnt hold up the worker
+async fn file_type(db: &Db, path: &Path) -> FileType {
+    let indexed = match path.metadata() {
+        Ok(meta) => {
+            db.detailed(&FileRecord::from_fs(path.to_path_buf(), &meta))
+                .await
+        }
+        Err(_) => None,
+    };
+
+    if let Some(record) = indexed {
+        return record.file_type;
+    }
+
+    let path = path.to_path_buf();
+
+    web::block({
+        let path = path.clone();
+        move || sniff::file_type(&path)
+    })
+    .await
+    .unwrap_or_else(|_| FileType::from(&path))
+}
+
+async fn search(
     args: web::Data<Args>,
     pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
 ) -> impl Responder {
-    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let root = PWD.read().unwrap().clone();
+    let q = query.q.clone().unwrap_or_default();
 
-    if let Some(path) = canonicalize_path(&path, &args, &PWD.read().unwrap(), false) {
-        let mut dirs = stat_all(visit_dir(&path));
+    let matcher = search::Matcher::new(&q, query.mode.unwrap_or_default());
 
-        log::debug!("serving path: {:?}", path);
+    let (results, error) = match matcher {
+        Ok(matcher) if !q.is_empty() => {
+            // without traversal only the root itself is visible, so thats all we search
+            let max_depth = if args.traverse { search::MAX_DEPTH } else { 0 };
 
-        dirs.sort_by_cached_key(|(path, _)| {
-            path.file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_ascii_lowercase()
-                .to_string()
-        });
-        dirs.sort_by_key(|(_, meta)| !meta.is_dir());
+            log::debug!("searching {:?} for {:?}", root, q);
 
-        let num_dirs = dirs.iter().fold(
-            0,
-            |acc, (path, _)| if path.is_dir() { acc + 1 } else { acc },
-        );
+            let results = db
+                .walk(&root, max_depth)
+                .await
+                .into_iter()
+                .filter(|record| matcher.is_match(&record.name()))
+                .collect();
 
-        let total_size = dirs.iter().fold(
-            0,
-            |acc, (_, meta)| if meta.is_dir() { acc } else { acc + meta.len() },
+            (
+                prepare_listing(db.annotate(results).await, &args, &query).await,
+                None,
+            )
+        }
+        Ok(_) => (vec![], None),
+        Err(err) => (vec![], Some(err)),
+    };
+
+    let footer_args = FooterArgs::from_entries(&results);
+    let (results, page) = listing::paginate(results, &query, args.page_size);
+
+    if fragment.fragment {
+        return fragment_response(
+            partials::entry_cards(&args, &query.without_search(), results, true),
+            &query,
+            &page,
         );
+    }
+
+    let pwd = display_root(&pwd.read().unwrap());
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &pwd,
+            &root,
+            footer_args,
+            partials::search_results(&args, &query, results, &page, error),
+        )
+        .into_string(),
+    )
+}
+
+#[derive(Serialize)]
+struct Fragment {
+    html: String,
+    // query string of the page after this one
+    next: Option<String>,
+}
+
+// What the grid script gets when it asks for the next page
+fn fragment_response(cards: maud::Markup, query: &ListingQuery, page: &Page) -> HttpResponse {
+    HttpResponse::Ok().json(Fragment {
+        html: cards.into_string(),
+        next: page.next().map(|next| query.with_page(next).query_string()),
+    })
+}
+
+// Browsers let any site post forms to a server on localhost, which would let it change tags,
+// ratings and the like behind the users back. so anything that isnt a read has to come from
+// a page with the same host, which a form on another site cant claim to be
+fn same_origin(req: &HttpRequest) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return true;
+    }
+
+    let headers = req.headers();
+
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+    else {
+        return false;
+    };
+
+    // referer is only there for the odd browser that leaves out the origin
+    headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|origin| origin.to_str().ok())
+        .and_then(|origin| origin.split_once("://"))
+        .and_then(|(_, rest)| rest.split('/').next())
+        .is_some_and(|origin| origin == host)
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    log::debug!("redirecting to {}", location);
+
+    HttpResponse::TemporaryRedirect()
+        .append_header(("Location", location))
+        .finish()
+}
+
+async fn index(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
+) -> impl Responder {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), false);
 
-        let mut pwd = pwd.read().unwrap().to_string_lossy().to_string();
-        let home = env::var("HOME").unwrap_or("/".to_string());
+    if let Some(path) = path {
+        let recursive = query.recursive(&args);
 
-        if pwd.starts_with(&home) {
-            pwd = pwd.replace(&home, "~");
+        let dirs = if recursive {
+            list_tree(&db, &path, &args, &query).await
+        } else {
+            list_dir(&db, &path, &args, &query).await
+        };
+
+        // totals are for the whole directory, not just the page
+        let footer_args = FooterArgs::from_entries(&dirs);
+
+        // the reader only goes through the dir itself, so flattened listings dont get one
+        let readable = !recursive
+            && dirs
+                .iter()
+                .any(|record| matches!(record.file_type, FileType::Image(_)));
+
+        let (dirs, page) = listing::paginate(dirs, &query, args.page_size);
+
+        if fragment.fragment {
+            let card_query = if recursive {
+                query.with_tree(&path)
+            } else {
+                query.clone().into_inner()
+            };
+
+            return fragment_response(
+                partials::entry_cards(&args, &card_query, dirs, recursive),
+                &query,
+                &page,
+            );
         }
 
+        let reading = if readable {
+            Some(db.reading(&path).await.unwrap_or_default())
+        } else {
+            None
+        };
+
+        // like a code forge, the readme goes on top of the first page
+        let readme = match markdown::find_readme(&path) {
+            Some(readme) if !recursive && page.number == 1 => web::block(move || {
+                let text = text::Text::read(&readme, text::MAX_BYTES).ok()?;
+                let html = markdown::render(&readme, &text.text);
+                Some((readme, html))
+            })
+            .await
+            .ok()
+            .flatten(),
+            _ => None,
+        };
+
+        let pwd = display_root(&pwd.read().unwrap());
+
         return HttpResponse::Ok().body(
             partials::page(
+                &args,
+                &query,
                 "iv",
                 &pwd,
                 &path,
-                FooterArgs {
-                    num_entries: dirs.len(),
-                    num_dirs,
-                    total_size,
-                },
-                partials::entry_grid(&args, dirs),
+                footer_args,
+                partials::listing(
+                    &args,
+                    &query,
+                    &path,
+                    dirs,
+                    &page,
+                    reading.as_ref(),
+                    readme
+                        .as_ref()
+                        .map(|(path, html)| (path.as_path(), html.as_str())),
+                ),
             )
             .into_string(),
         );
     }
 
-    log::debug!("redirecting to /");
+    redirect("/")
+}
+
+async fn view(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> impl Responder {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!view").unwrap_or(&path));
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), true);
+
+    let Some(path) = path else {
+        return redirect("/");
+    };
+
+    if !matches!(file_type(&db, &path).await, FileType::Image(_)) {
+        return redirect("/");
+    }
+
+    let root = PWD.read().unwrap().clone();
+
+    let entries = if query.recursive(&args) {
+        // the flattened listing the image was opened from, or its own dir if that isnt known
+        let dir = query
+            .tree
+            .as_ref()
+            .and_then(|tree| canonicalize_path(Path::new(tree), &args, &root, false))
+            .filter(|tree| path.starts_with(tree))
+            .unwrap_or_else(|| path.parent().unwrap().to_path_buf());
+
+        list_tree(&db, &dir, &args, &query).await
+    } else {
+        list_dir(&db, path.parent().unwrap(), &args, &query).await
+    };
+
+    let dir = path.parent().unwrap().to_path_buf();
+
+    let mut footer_args = FooterArgs::from_entries(&entries);
+
+    // prev/next only step through images, in the same order as the grid
+    let images = entries
+        .into_iter()
+        .filter(|record| matches!(record.file_type, FileType::Image(_)))
+        .collect::<Vec<_>>();
+
+    let Some(position) = images.iter().position(|record| record.path == path) else {
+        return redirect(&format!(
+            "/{}{}",
+            partials::url_path(&dir),
+            query.query_string()
+        ));
+    };
+
+    footer_args.position = Some((position + 1, images.len()));
+
+    let pwd = display_root(&pwd.read().unwrap());
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &pwd,
+            &dir,
+            footer_args,
+            partials::viewer(&query, &images, position),
+        )
+        .into_string(),
+    )
+}
+
+// Text files and source code, highlighted or rendered, with anything past text::MAX_BYTES cut off
+async fn text_preview(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> impl Responder {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!text").unwrap_or(&path));
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), true);
+
+    let Some(path) = path else {
+        return redirect("/");
+    };
+
+    if !text::is_text(file_type(&db, &path).await.mime()) {
+        return redirect("/");
+    }
+
+    let dir = path.parent().unwrap().to_path_buf();
+
+    let Some(record) = file_record(&db, &path).await else {
+        return redirect(&format!(
+            "/{}{}",
+            partials::url_path(&dir),
+            query.query_string()
+        ));
+    };
+
+    let preview = web::block(move || {
+        let text = text::Text::read(&path, text::MAX_BYTES)?;
+        let syntax = text::syntax_name(&path, &text.text);
+
+        // markdown is rendered, everything else highlighted
+        let content = if markdown::is_markdown(&path) {
+            partials::markdown(&markdown::render(&path, &text.text))
+        } else {
+            partials::code(&text::highlight(&path, &text.text))
+        };
+
+        Ok::<_, db::Error>((text, syntax, content))
+    })
+    .await;
+
+    // binary files that only look like text by name are left to the browser
+    let Ok(Ok((text, syntax, content))) = preview else {
+        return redirect(&format!("/!_/{}", partials::url_path(&record.path)));
+    };
+
+    let pwd = display_root(&pwd.read().unwrap());
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &pwd,
+            &dir,
+            FooterArgs::from_entries(std::slice::from_ref(&record)),
+            partials::text_preview(&query, &record, &text, syntax, content),
+        )
+        .into_string(),
+    )
+}
+
+// The dir (or archive) a reader url points at, if its one
+fn reader_dir(req: &HttpRequest, args: &Args) -> Option<PathBuf> {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!read").unwrap_or(&path));
+
+    // files dont get swapped for their dir here, reading a single page makes no sense
+    canonicalize_path(&path, args, &PWD.read().unwrap(), true)
+        .filter(|path| path.is_dir() || (args.traverse && archive::is_dir(path)))
+}
+
+// Reads the images of a dir or archive like a book, in the same order as the grid
+async fn read(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> impl Responder {
+    let Some(dir) = reader_dir(&req, &args) else {
+        return redirect("/");
+    };
+
+    let pages = list_dir(&db, &dir, &args, &query)
+        .await
+        .into_iter()
+        .filter(|record| matches!(record.file_type, FileType::Image(_)))
+        .collect::<Vec<_>>();
+
+    if pages.is_empty() {
+        return redirect(&format!(
+            "/{}{}",
+            partials::url_path(&dir),
+            query.query_string()
+        ));
+    }
+
+    let reading = db.reading(&dir).await.unwrap_or_default();
+
+    let mut footer_args = FooterArgs::from_entries(&pages);
+    footer_args.position = Some((reading.position(&pages) + 1, pages.len()));
+
+    let pwd = display_root(&pwd.read().unwrap());
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &pwd,
+            &dir,
+            footer_args,
+            partials::reader(&query, &dir, &pages, &reading),
+        )
+        .into_string(),
+    )
+}
+
+#[derive(Deserialize)]
+struct ReadForm {
+    page: Option<String>,
+    mode: reader::ReadMode,
+    rtl: bool,
+}
+
+// Remembers where the reader is at, so the next visit picks up there
+async fn save_reading(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    form: web::Form<ReadForm>,
+) -> actix_web::Result<HttpResponse> {
+    let dir = reader_dir(&req, &args).ok_or(actix_web::error::ErrorNotFound("404 Not Found"))?;
+    let form = form.into_inner();
+
+    db.save_reading(
+        &dir,
+        &reader::Reading {
+            page: form.page,
+            mode: form.mode,
+            rtl: form.rtl,
+        },
+    )
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Server sent events for everything that changes directly in a directory
+async fn events(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    watcher: web::Data<watch::Watcher>,
+) -> impl Responder {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!events").unwrap_or(&path));
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), false);
+
+    let Some(path) = path else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .streaming(watch::event_stream(watcher.subscribe(&path)))
+}
+
+#[derive(Deserialize)]
+struct CardQuery {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Card {
+    // none if the file is gone or the listing filters it out
+    html: Option<String>,
+    // name of the entry that comes after it in the listing, none if its last
+    before: Option<String>,
+}
+
+// A single freshly rendered card, and where it goes, for the live reload script
+async fn card(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    card: web::Query<CardQuery>,
+) -> impl Responder {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!card").unwrap_or(&path));
+    let dir = canonicalize_path(&path, &args, &PWD.read().unwrap(), false);
+
+    let Some(dir) = dir.filter(|_| !card.name.contains('/') && card.name != "..") else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let path = dir.join(&card.name);
+
+    let Some(mut record) = file_record(&db, &path).await else {
+        return HttpResponse::Ok().json(Card {
+            html: None,
+            before: None,
+        });
+    };
+
+    // the index may still have the old size and mtime of a file thats being written, so the
+    // snippet isnt in there yet either. a single one is cheap enough to read here
+    if record.snippet.is_none() && text::is_text(record.file_type.mime()) {
+        let fresh = record.clone();
+        record.snippet = web::block(move || text::snippet(&fresh))
+            .await
+            .ok()
+            .flatten();
+    }
+
+    // the card goes in front of the entry that follows it in the listing, unless the query
+    // filters it out. the index knows the rest of the dir well enough for that
+    let siblings = db
+        .list_dir(&dir)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|sibling| sibling.path != path)
+        .chain([record])
+        .collect();
+    let entries = prepare_listing(db.annotate(siblings).await, &args, &query).await;
+
+    let (html, before) = match entries.iter().position(|record| record.path == path) {
+        Some(position) => (
+            Some(
+                partials::entry_cards(&args, &query, vec![entries[position].clone()], false)
+                    .into_string(),
+            ),
+            entries.get(position + 1).map(FileRecord::name),
+        ),
+        None => (None, None),
+    };
+
+    HttpResponse::Ok().json(Card { html, before })
+}
+
+#[derive(Deserialize)]
+struct TagForm {
+    tag: String,
+}
+
+#[derive(Deserialize)]
+struct RenameForm {
+    to: String,
+}
+
+#[derive(Serialize)]
+struct Tags {
+    tags: Vec<String>,
+    // the re-rendered editor, so the script doesnt need its own copy of the markup
+    html: String,
+}
+
+// tag and album names are free text, but they end up in urls
+fn clean_name(name: &str) -> actix_web::Result<String> {
+    let name = name.trim();
+
+    if name.is_empty() || name.len() > 64 || name.contains('/') || name.contains(char::is_control) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Names must be 1 to 64 characters, without slashes",
+        ));
+    }
+
+    Ok(name.to_string())
+}
+
+// The file a url like /!tag/{path} points at
+fn target_file(
+    req: &HttpRequest,
+    args: &Args,
+    db: &Db,
+    prefix: &str,
+) -> actix_web::Result<FileRecord> {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix(prefix).unwrap_or(&path));
+
+    let path = canonicalize_path(&path, args, &PWD.read().unwrap(), true)
+        .filter(|path| !path.is_dir())
+        .ok_or(actix_web::error::ErrorNotFound("404 Not Found"))?;
+
+    let meta = path.metadata()?;
+
+    // tags and the like are found again through the index, so it has to know about the file
+    db.refresh_in_background(path.parent().unwrap().to_path_buf());
+
+    Ok(FileRecord::from_fs(path, &meta))
+}
+
+async fn tags_response(db: &Db, record: FileRecord) -> HttpResponse {
+    let record = db.annotate(vec![record]).await.remove(0);
+
+    HttpResponse::Ok().json(Tags {
+        html: partials::tag_editor(&record).into_string(),
+        tags: record.tags,
+    })
+}
+
+async fn get_tags(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!tag")?;
+
+    Ok(tags_response(&db, record).await)
+}
+
+async fn add_tag(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    form: web::Form<TagForm>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!tag")?;
+
+    db.add_tag(&record, &clean_name(&form.tag)?)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(tags_response(&db, record).await)
+}
+
+async fn remove_tag(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<TagForm>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!tag")?;
+
+    db.remove_tag(&record, &query.tag)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(tags_response(&db, record).await)
+}
+
+// The tag a /!tags/{tag} url is about
+fn tag_name(req: &HttpRequest) -> String {
+    let path = String::from(urlencoding::decode(req.path()).unwrap());
+    path.strip_prefix("/!tags/").unwrap_or(&path).to_string()
+}
+
+async fn tag_index(
+    args: web::Data<Args>,
+    pwd: web::Data<Arc<RwLock<PathBuf>>>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let tags = db
+        .all_tags()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let root = PWD.read().unwrap().clone();
+    let pwd = display_root(&pwd.read().unwrap());
+
+    Ok(HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &pwd,
+            &root,
+            FooterArgs::from_entries(&[]),
+            partials::tag_index(&tags),
+        )
+        .into_string(),
+    ))
+}
+
+// Drops files that couldnt be reached by browsing, so without traversal anything below the root
+fn reachable(args: &Args, records: Vec<FileRecord>) -> Vec<FileRecord> {
+    let root = PWD.read().unwrap().clone();
+
+    records
+        .into_iter()
+        .filter(|record| {
+            canonicalize_path(Path::new(&db::rel_path(&record.path)), args, &root, true).is_some()
+        })
+        .collect()
+}
+
+// Renders files gathered from anywhere under the root, like everything with a tag, as a listing.
+// ordered collections keep their own order unless a sort was picked
+async fn collection_response(
+    args: &Args,
+    db: &Db,
+    query: &ListingQuery,
+    fragment: &FragmentQuery,
+    records: Vec<FileRecord>,
+    ordered: bool,
+    heading: maud::Markup,
+) -> HttpResponse {
+    let root = PWD.read().unwrap().clone();
+    let records = db.annotate(reachable(args, records)).await;
+
+    let records = if ordered && query.sort.is_none() {
+        query.filter_entries(records)
+    } else {
+        prepare_listing(records, args, query).await
+    };
+
+    let footer_args = FooterArgs::from_entries(&records);
+    let (records, page) = listing::paginate(records, query, args.page_size);
+
+    if fragment.fragment {
+        return fragment_response(
+            partials::entry_cards(args, query, records, true),
+            query,
+            &page,
+        );
+    }
+
+    HttpResponse::Ok().body(
+        partials::page(
+            args,
+            query,
+            "iv",
+            &display_root(&root),
+            &root,
+            footer_args,
+            partials::collection(args, query, heading, records, &page),
+        )
+        .into_string(),
+    )
+}
+
+async fn tagged(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
+) -> impl Responder {
+    let tag = tag_name(&req);
+    let records = db.tagged(&tag).await;
+
+    collection_response(
+        &args,
+        &db,
+        &query,
+        &fragment,
+        records,
+        false,
+        partials::tag_heading(&tag),
+    )
+    .await
+}
+
+async fn rename_tag(
+    req: HttpRequest,
+    db: web::Data<Db>,
+    form: web::Form<RenameForm>,
+) -> actix_web::Result<HttpResponse> {
+    db.rename_tag(&tag_name(&req), &clean_name(&form.to)?)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn delete_tag(req: HttpRequest, db: web::Data<Db>) -> actix_web::Result<HttpResponse> {
+    db.delete_tag(&tag_name(&req))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct RateForm {
+    rating: Option<u8>,
+    favourite: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Rating {
+    rating: u8,
+    favourite: bool,
+    html: String,
+}
+
+// Sets the rating and/or favourite flag of a file, answers with the re-rendered controls
+async fn rate(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    form: web::Form<RateForm>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!rate")?;
+
+    if form.rating.is_some_and(|rating| rating > 5) {
+        return Err(actix_web::error::ErrorBadRequest("Ratings go from 0 to 5"));
+    }
+
+    db.rate(&record, form.rating, form.favourite)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let record = db.annotate(vec![record]).await.remove(0);
+
+    Ok(HttpResponse::Ok().json(Rating {
+        rating: record.rating,
+        favourite: record.favourite,
+        html: partials::rating_editor(&record).into_string(),
+    }))
+}
+
+async fn favourites(
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
+) -> impl Responder {
+    let records = db.favourites().await;
+
+    collection_response(
+        &args,
+        &db,
+        &query,
+        &fragment,
+        records,
+        false,
+        partials::favourites_heading(),
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct AlbumForm {
+    album: String,
+}
+
+#[derive(Serialize)]
+struct Albums {
+    albums: Vec<String>,
+    html: String,
+}
+
+async fn albums_response(db: &Db, record: FileRecord) -> HttpResponse {
+    let record = db.annotate(vec![record]).await.remove(0);
+
+    HttpResponse::Ok().json(Albums {
+        html: partials::album_editor(&record).into_string(),
+        albums: record.albums,
+    })
+}
+
+async fn add_to_album(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    form: web::Form<AlbumForm>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!album")?;
+
+    db.add_to_album(&clean_name(&form.album)?, &record)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(albums_response(&db, record).await)
+}
+
+async fn remove_from_album(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<AlbumForm>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!album")?;
+
+    db.remove_from_album(&query.album, &record)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(albums_response(&db, record).await)
+}
+
+// The album a /!albums/{name} url is about
+fn album_name(req: &HttpRequest) -> String {
+    let path = String::from(urlencoding::decode(req.path()).unwrap());
+    let path = path.strip_prefix("/!albums/").unwrap_or(&path);
+    path.strip_suffix("/export").unwrap_or(path).to_string()
+}
+
+async fn album_index(
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let albums = db
+        .all_albums()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let root = PWD.read().unwrap().clone();
+
+    Ok(HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &display_root(&root),
+            &root,
+            FooterArgs::from_entries(&[]),
+            partials::album_index(&albums),
+        )
+        .into_string(),
+    ))
+}
+
+async fn album(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
+) -> impl Responder {
+    let name = album_name(&req);
+    let records = db.album(&name).await;
+
+    collection_response(
+        &args,
+        &db,
+        &query,
+        &fragment,
+        records,
+        true,
+        partials::album_heading(&name),
+    )
+    .await
+}
+
+// An album as a plain list of root relative paths, one per line
+async fn export_album(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> impl Responder {
+    let records = reachable(&args, db.album(&album_name(&req)).await);
+
+    let mut list = String::new();
+    for record in records {
+        list.push_str(&db::rel_path(&record.path));
+        list.push('\n');
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(list)
+}
+
+async fn delete_album(req: HttpRequest, db: web::Data<Db>) -> actix_web::Result<HttpResponse> {
+    db.delete_album(&album_name(&req))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Annotates groups of records with a single lookup, keeping them grouped
+async fn annotate_groups(db: &Db, groups: Vec<Vec<FileRecord>>) -> Vec<Vec<FileRecord>> {
+    let sizes: Vec<usize> = groups.iter().map(Vec::len).collect();
+    let mut records = db
+        .annotate(groups.into_iter().flatten().collect())
+        .await
+        .into_iter();
+
+    sizes
+        .into_iter()
+        .map(|size| records.by_ref().take(size).collect())
+        .collect()
+}
+
+// groups rather than files, each one is a grid of its own
+const GROUPS_PER_PAGE: usize = 20;
+
+// Clusters of visually similar images across the whole root
+async fn duplicates(
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> impl Responder {
+    let root = PWD.read().unwrap().clone();
+    let distance = query.distance.unwrap_or(args.similarity).min(64);
+
+    // only whats indexed has a hash, so get the rest of the tree going for next time
+    let pending = db
+        .index_tree(&root, if args.traverse { search::MAX_DEPTH } else { 0 })
+        .await;
+
+    let images = reachable(&args, db.hashed_images().await);
+
+    let groups: Vec<Vec<FileRecord>> = web::block(move || {
+        similar::cluster(images, distance)
+            .into_iter()
+            // the index can still have files that were deleted since it last looked
+            .map(|group| {
+                group
+                    .into_iter()
+                    .filter(|record| record.path.exists())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| group.len() > 1)
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+
+    let footer_args = FooterArgs::from_entries(&groups.concat());
+
+    let (groups, page) = listing::paginate(groups, &query, GROUPS_PER_PAGE);
+    let groups = annotate_groups(&db, groups).await;
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &display_root(&root),
+            &root,
+            footer_args,
+            partials::duplicates(&args, &query, groups, &page, distance, pending),
+        )
+        .into_string(),
+    )
+}
+
+// Sets of byte identical files across the whole root, and how much space they waste
+async fn exact_duplicates(
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+) -> impl Responder {
+    let root = PWD.read().unwrap().clone();
+
+    // a directory that isnt indexed yet has nothing to hash, so its picked up next time
+    let pending = db
+        .index_tree(&root, if args.traverse { search::MAX_DEPTH } else { 0 })
+        .await;
+    db.hash_in_background();
+
+    let groups = db
+        .identical_files()
+        .await
+        .into_iter()
+        .map(|group| {
+            reachable(&args, group)
+                .into_iter()
+                .filter(|record| record.path.exists())
+                .collect::<Vec<_>>()
+        })
+        .filter(|group| group.len() > 1)
+        .collect();
+
+    let mut groups = annotate_groups(&db, groups)
+        .await
+        .into_iter()
+        .map(|records| {
+            let copies = records
+                .iter()
+                .map(|record| (record.dev, record.inode))
+                .collect::<std::collections::HashSet<_>>()
+                .len() as u64;
+
+            partials::DuplicateGroup {
+                reclaimable: records[0].size * (copies - 1),
+                records,
+            }
+        })
+        .filter(|group| group.reclaimable > 0)
+        .collect::<Vec<_>>();
+
+    groups.sort_by_key(|group| std::cmp::Reverse(group.reclaimable));
+
+    let mut footer_args = FooterArgs::from_entries(
+        &groups
+            .iter()
+            .flat_map(|group| group.records.iter().cloned())
+            .collect::<Vec<_>>(),
+    );
+    footer_args.reclaimable = Some(groups.iter().map(|group| group.reclaimable).sum());
+
+    HttpResponse::Ok().body(
+        partials::page(
+            &args,
+            &query,
+            "iv",
+            &display_root(&root),
+            &root,
+            footer_args,
+            partials::exact_duplicates(
+                &args,
+                &query,
+                groups,
+                pending,
+                db.is_hashing(),
+                db.unhashed_candidates().await,
+            ),
+        )
+        .into_string(),
+    )
+}
+
+// Every image that looks like the given one, closest first
+async fn find_similar(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+    query: web::Query<ListingQuery>,
+    fragment: web::Query<FragmentQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!similar")?;
+    let distance = query.distance.unwrap_or(args.similarity).min(64);
+
+    // hashed right now rather than looked up, so it works before the directory is indexed
+    let phash = web::block({
+        let path = record.path.clone();
+        move || similar::dhash_file(&path)
+    })
+    .await?
+    .map_err(actix_web::error::ErrorNotFound)?;
+
+    let mut matches: Vec<(u32, FileRecord)> = db
+        .hashed_images()
+        .await
+        .into_iter()
+        .filter(|other| other.path != record.path)
+        .filter_map(|other| {
+            let d = similar::distance(phash, other.phash?);
+            (d <= distance && other.path.exists()).then_some((d, other))
+        })
+        .collect();
+
+    matches.sort_by_key(|(d, _)| *d);
+
+    let records = std::iter::once(record.clone())
+        .chain(matches.into_iter().map(|(_, other)| other))
+        .collect();
+
+    Ok(collection_response(
+        &args,
+        &db,
+        &query,
+        &fragment,
+        records,
+        true,
+        partials::similar_heading(&record, distance),
+    )
+    .await)
+}
+
+#[derive(Serialize)]
+struct MetaResponse {
+    path: String,
+    size: u64,
+    mime: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    meta: Option<meta::Meta>,
+}
 
-    return HttpResponse::TemporaryRedirect()
-        .append_header(("Location", "/"))
-        .finish();
+// The same details the viewer panel shows, as json
+async fn api_meta(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> actix_web::Result<HttpResponse> {
+    let record = target_file(&req, &args, &db, "/!api/meta")?;
+
+    let record = match db.detailed(&record).await {
+        Some(record) => record,
+        None => {
+            web::block(move || {
+                let mut record = record;
+                record.compute_details();
+                record
+            })
+            .await?
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(MetaResponse {
+        path: db::rel_path(&record.path),
+        size: record.size,
+        mime: record.file_type.mime().to_string(),
+        width: record.width,
+        height: record.height,
+        meta: record.meta,
+    }))
 }
 
 #[cfg(not(debug_assertions))]
@@ -334,7 +1723,11 @@ async fn assets(req: HttpRequest) -> actix_web::Result<NamedFile> {
     }
 }
 
-async fn file(req: HttpRequest, args: web::Data<Args>) -> actix_web::Result<NamedFile> {
+async fn file(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> actix_web::Result<HttpResponse> {
     let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
     let path = PathBuf::from(path.strip_prefix("/!_").unwrap_or(&path));
 
@@ -342,13 +1735,144 @@ async fn file(req: HttpRequest, args: web::Data<Args>) -> actix_web::Result<Name
         return Err(actix_web::error::ErrorNotFound("404 Not Found"));
     }
 
-    if let Some(path) = canonicalize_path(&path, &args, &PWD.read().unwrap(), true) {
-        Ok(NamedFile::open(path)?)
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), true);
+
+    if let Some(path) = path {
+        // members of archives come straight out of them, without being extracted anywhere
+        if archive::member(&path).is_some() {
+            let (size, stream) = archive::stream(&path).map_err(|err| {
+                log::debug!("failed to read {:?}: {}", path, err);
+                actix_web::error::ErrorNotFound("404 Not Found")
+            })?;
+
+            return Ok(HttpResponse::Ok()
+                .content_type(FileType::from(&path).mime())
+                .no_chunking(size)
+                .streaming(stream));
+        }
+
+        let file_type = file_type(&db, &path).await;
+        let mut file = NamedFile::open(&path)?;
+
+        // the file says its something else than its extension does
+        if file.content_type().essence_str() != file_type.mime() {
+            if let Ok(mime) = file_type.mime().parse() {
+                file = file.set_content_type(mime);
+            }
+
+            // shown in the browser like any other image (or text, ...) instead of downloaded
+            if !matches!(file_type, FileType::Unknown(_)) || text::is_text(file_type.mime()) {
+                let cd = ContentDisposition {
+                    disposition: DispositionType::Inline,
+                    parameters: file.content_disposition().parameters.clone(),
+                };
+                file = file.set_content_disposition(cd);
+            }
+        }
+
+        Ok(file.into_response(&req))
     } else {
         Err(actix_web::error::ErrorNotFound("404 Not Found"))
     }
 }
 
+// The placeholder for an image that cant be converted, not cached so it goes away once the
+// format can be decoded (like after building with the heif feature)
+fn unsupported_image(file_type: &FileType) -> HttpResponse {
+    HttpResponse::UnsupportedMediaType()
+        .append_header(("Content-Type", "image/svg+xml"))
+        .append_header(("Cache-Control", "no-store"))
+        .body(partials::unsupported_image(file_type.mime()).into_string())
+}
+
+async fn thumb(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> actix_web::Result<HttpResponse> {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!thumb").unwrap_or(&path));
+
+    let mut components = path.iter();
+
+    let size = components
+        .next()
+        .and_then(|size| thumbs::ThumbSize::parse(&size.to_string_lossy()))
+        .ok_or(actix_web::error::ErrorNotFound("404 Not Found"))?;
+
+    let path: PathBuf = components.collect();
+
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), true)
+        .filter(|path| !path.is_dir())
+        .ok_or(actix_web::error::ErrorNotFound("404 Not Found"))?;
+
+    let file_type = file_type(&db, &path).await;
+
+    // audio files get the cover art thats in them
+    if !matches!(file_type, FileType::Image(_) | FileType::Audio(_)) {
+        return Err(actix_web::error::ErrorNotFound("404 Not Found"));
+    }
+
+    let thumb = web::block({
+        let args = args.clone();
+        let path = path.clone();
+        move || thumbs::get_or_create(&args, &path, size)
+    })
+    .await?;
+
+    match thumb {
+        Ok(thumb) => Ok(NamedFile::open(thumb)?.into_response(&req)),
+        Err(err) => {
+            log::debug!("failed to generate thumbnail for {:?}: {}", path, err);
+
+            // things like svg cant be decoded, so just hand out the original if the browser
+            // can show it
+            match file_type {
+                FileType::Image(_) if convert::browser_native(file_type.mime()) => {
+                    Ok(NamedFile::open(path)?.into_response(&req))
+                }
+                FileType::Image(_) => Ok(unsupported_image(&file_type)),
+                _ => Err(actix_web::error::ErrorNotFound("404 Not Found")),
+            }
+        }
+    }
+}
+
+// Browser friendly copy of an image the browser cant show by itself
+async fn converted(
+    req: HttpRequest,
+    args: web::Data<Args>,
+    db: web::Data<Db>,
+) -> actix_web::Result<HttpResponse> {
+    let path = PathBuf::from(String::from(urlencoding::decode(req.path()).unwrap()));
+    let path = PathBuf::from(path.strip_prefix("/!convert").unwrap_or(&path));
+
+    let path = canonicalize_path(&path, &args, &PWD.read().unwrap(), true)
+        .filter(|path| !path.is_dir())
+        .ok_or(actix_web::error::ErrorNotFound("404 Not Found"))?;
+
+    let file_type = file_type(&db, &path).await;
+
+    if !matches!(file_type, FileType::Image(_)) {
+        return Err(actix_web::error::ErrorNotFound("404 Not Found"));
+    }
+
+    let converted = web::block({
+        let args = args.clone();
+        let path = path.clone();
+        move || convert::get_or_create(&args, &path)
+    })
+    .await?;
+
+    match converted {
+        Ok(converted) => Ok(NamedFile::open(converted)?.into_response(&req)),
+        Err(err) => {
+            log::debug!("failed to convert {:?}: {}", path, err);
+            Ok(unsupported_image(&file_type))
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     println!("iv~~!");
@@ -373,6 +1897,8 @@ async fn main() -> std::io::Result<()> {
     )
     .unwrap();
 
+    convert::register_decoders();
+
     log::debug!("args: {:?}", args);
     log::debug!("pwd: {:?}", env::current_dir().unwrap());
 
@@ -381,6 +1907,11 @@ async fn main() -> std::io::Result<()> {
         PWD.read().unwrap().to_string_lossy()
     );
 
+    let root = PWD.read().unwrap().clone();
+    let db = Db::open(&args, &root).await.expect("failed to open index");
+
+    let watcher = Data::new(watch::Watcher::new().expect("failed to start watching for changes"));
+
     if !args.no_open {
         std::thread::spawn({
             let args = args.clone();
@@ -395,17 +1926,78 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new({
         let args = args.clone();
+        let db = db.clone();
+        let watcher = watcher.clone();
         move || {
             App::new()
                 .app_data(Data::new(args.clone()))
                 .app_data(Data::new(PWD.clone()))
+                .app_data(Data::new(db.clone()))
+                .app_data(watcher.clone())
+                .wrap_fn(|req, srv| {
+                    if same_origin(req.request()) {
+                        Either::Left(srv.call(req))
+                    } else {
+                        log::warn!("refused {} {} from another site", req.method(), req.path());
+                        Either::Right(ready(Err(actix_web::error::ErrorForbidden(
+                            "403 Forbidden",
+                        ))))
+                    }
+                })
                 .default_service(web::route().to(index))
                 // FUCK me if someone uses _! to prefix a filename
                 .service(web::resource("/_!/{path:.*}").to(assets))
                 .service(web::resource("/!_/{path:.*}").to(file))
+                .service(web::resource("/!thumb/{size}/{path:.*}").to(thumb))
+                .service(web::resource("/!convert/{path:.*}").to(converted))
+                .service(web::resource("/!view/{path:.*}").to(view))
+                .service(web::resource("/!text/{path:.*}").to(text_preview))
+                .service(
+                    web::resource("/!read/{path:.*}")
+                        .route(web::get().to(read))
+                        .route(web::post().to(save_reading)),
+                )
+                .service(web::resource("/!search").to(search))
+                .service(web::resource("/!events/{path:.*}").to(events))
+                .service(web::resource("/!card/{path:.*}").to(card))
+                .service(
+                    web::resource("/!tag/{path:.*}")
+                        .route(web::get().to(get_tags))
+                        .route(web::post().to(add_tag))
+                        .route(web::delete().to(remove_tag)),
+                )
+                .service(web::resource("/!rate/{path:.*}").route(web::post().to(rate)))
+                .service(web::resource("/!favourites").to(favourites))
+                .service(web::resource("/!api/meta/{path:.*}").to(api_meta))
+                .service(web::resource("/!duplicates").to(duplicates))
+                .service(web::resource("/!duplicates/exact").to(exact_duplicates))
+                .service(web::resource("/!similar/{path:.*}").to(find_similar))
+                .service(
+                    web::resource("/!album/{path:.*}")
+                        .route(web::post().to(add_to_album))
+                        .route(web::delete().to(remove_from_album)),
+                )
+                .service(web::resource("/!albums").to(album_index))
+                .service(web::resource("/!albums/{name}/export").to(export_album))
+                .service(
+                    web::resource("/!albums/{name}")
+                        .route(web::get().to(album))
+                        .route(web::delete().to(delete_album)),
+                )
+                .service(web::resource("/!tags").to(tag_index))
+                .service(
+                    web::resource("/!tags/{tag}")
+                        .route(web::get().to(tagged))
+                        .route(web::put().to(rename_tag))
+                        .route(web::delete().to(delete_tag)),
+                )
         }
     })
     .bind((args.host, args.port))?
     .run()
-    .await
+    .await?;
+
+    db.close().await;
+
+    Ok(())
 }
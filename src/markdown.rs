@@ -0,0 +1,236 @@
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+};
+
+use pulldown_cmark::{Options, Parser};
+
+use crate::{archive, partials::FileType, text, PWD};
+
+const EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+// what gets shown above the grid, compared case insensitively
+const README_NAMES: [&str; 2] = ["readme.md", "readme.markdown"];
+
+pub fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+// The readme of a dir on disk, if it has one
+pub fn find_readme(dir: &Path) -> Option<PathBuf> {
+    dir.read_dir()
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        README_NAMES
+                            .iter()
+                            .any(|known| known.eq_ignore_ascii_case(name))
+                    })
+        })
+}
+
+// Renders markdown to html thats safe to put in a page. links and images relative to the
+// file are pointed at where the server has them
+pub fn render(path: &Path, markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(markdown, options));
+
+    let dir = path.parent().unwrap_or(path).to_path_buf();
+    let root = PWD.read().unwrap().clone();
+
+    // this runs over raw html in the markdown as well, not just over markdown links
+    ammonia::Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .attribute_filter(move |element, attribute, value| {
+            let image = match (element, attribute) {
+                ("img", "src") => true,
+                ("a", "href") => false,
+                _ => return Some(Cow::Borrowed(value)),
+            };
+
+            Some(resolve(&root, &dir, value, image).map_or(Cow::Borrowed(value), Cow::Owned))
+        })
+        .clean(&html)
+        .to_string()
+}
+
+// Where a relative url in a markdown file in dir really points, nothing for urls that arent
+// relative or that leave the served root
+fn resolve(root: &Path, dir: &Path, url: &str, image: bool) -> Option<String> {
+    // schemes, root relative paths and anchors in the same document stay as they are
+    let scheme = url
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.contains(['/', '?', '#']));
+
+    if url.is_empty() || scheme || url.starts_with(['/', '#', '?']) {
+        return None;
+    }
+
+    let split = url.find(['?', '#']).unwrap_or(url.len());
+    let (target, suffix) = url.split_at(split);
+    let target = urlencoding::decode(target).map_or(Cow::Borrowed(target), |target| target);
+
+    let mut path = dir.strip_prefix(root).ok()?.to_path_buf();
+
+    for component in Path::new(target.as_ref()).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir if path.pop() => {}
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    let url = urlencoding::encode(&path.to_string_lossy()).replace("%2F", "/");
+    let path = root.join(path);
+
+    let prefix = if image {
+        "/!_/"
+    } else if path.is_dir() || archive::is_dir(&path) {
+        "/"
+    } else {
+        match FileType::from(&path) {
+            FileType::Image(_) => "/!view/",
+            file_type if text::is_text(file_type.mime()) => "/!text/",
+            _ => "/!_/",
+        }
+    };
+
+    Some(format!("{}{}{}", prefix, url, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a served root with a docs dir in it
+    fn root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("iv-markdown-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs/img")).unwrap();
+        root
+    }
+
+    #[test]
+    fn relative_targets() {
+        let root = root();
+        let docs = root.join("docs");
+
+        assert_eq!(
+            resolve(&root, &docs, "guide.md", false),
+            Some("/!text/docs/guide.md".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "./img/a.png", true),
+            Some("/!_/docs/img/a.png".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "img/a.png", false),
+            Some("/!view/docs/img/a.png".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "img", false),
+            Some("/docs/img".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "../notes.txt", false),
+            Some("/!text/notes.txt".into())
+        );
+    }
+
+    #[test]
+    fn above_root() {
+        let root = root();
+
+        assert_eq!(
+            resolve(&root, &root.join("docs"), "../../etc/passwd", false),
+            None
+        );
+        assert_eq!(resolve(&root, &root, "../x.md", true), None);
+        // climbing out and back in still went through something outside
+        assert_eq!(
+            resolve(&root, &root.join("docs"), "../../docs/a.md", false),
+            None
+        );
+    }
+
+    #[test]
+    fn left_alone() {
+        let root = root();
+        let docs = root.join("docs");
+
+        for url in [
+            "https://example.com/a.png",
+            "mailto:someone@example.com",
+            "javascript:alert(1)",
+            "/docs/a.md",
+            "//example.com/a.png",
+            "#heading",
+            "?page=2",
+            "",
+        ] {
+            assert_eq!(resolve(&root, &docs, url, false), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn suffixes() {
+        let root = root();
+        let docs = root.join("docs");
+
+        assert_eq!(
+            resolve(&root, &docs, "guide.md#install", false),
+            Some("/!text/docs/guide.md#install".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "img/a.png?v=2", true),
+            Some("/!_/docs/img/a.png?v=2".into())
+        );
+        // a colon after the path starts isnt a scheme
+        assert_eq!(
+            resolve(&root, &docs, "guide.md#a:b", false),
+            Some("/!text/docs/guide.md#a:b".into())
+        );
+    }
+
+    #[test]
+    fn percent_encoded() {
+        let root = root();
+        let docs = root.join("docs");
+
+        assert_eq!(
+            resolve(&root, &docs, "my%20notes.md", false),
+            Some("/!text/docs/my%20notes.md".into())
+        );
+        assert_eq!(
+            resolve(&root, &docs, "img%2Fa.png", true),
+            Some("/!_/docs/img/a.png".into())
+        );
+        // dots hidden behind escapes dont get past the check either
+        assert_eq!(
+            resolve(&root, &docs, "%2e%2e/%2e%2e/etc/passwd", false),
+            None
+        );
+        assert_eq!(
+            resolve(&root, &docs, "%2e%2e/notes.txt", false),
+            Some("/!text/notes.txt".into())
+        );
+    }
+}
@@ -0,0 +1,641 @@
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read},
+    path::Path,
+};
+
+use exif::{Exif, In, Tag, Value};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{audio, raw};
+
+// xmp packets are near the start of jpegs, but can be anywhere in other formats
+const XMP_SCAN_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Gps {
+    pub latitude: f64,
+    pub longitude: f64,
+    // meters above sea level
+    pub altitude: Option<f64>,
+}
+
+// What the embedded EXIF, XMP and IPTC metadata of an image says, merged into one.
+// stored in the index as json, so only add fields with a serde default
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Meta {
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub exposure: Option<String>,
+    pub aperture: Option<String>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<String>,
+    // as written by the camera, which is local time without a zone
+    pub taken_at: Option<String>,
+    pub gps: Option<Gps>,
+    // exif orientation, 1 to 8
+    pub orientation: Option<u16>,
+    pub software: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub creator: Option<String>,
+    pub copyright: Option<String>,
+    pub keywords: Vec<String>,
+    // 0 to 5 stars, as set by other photo tools
+    pub rating: Option<u8>,
+    // which camera raw format the file is, for raws
+    pub raw: Option<String>,
+    // audio only, see audio::meta
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // seconds
+    pub duration: Option<f64>,
+    // whether theres embedded cover art to make a thumbnail from
+    pub cover: bool,
+}
+
+impl Meta {
+    // Reads whatever metadata the file has, none if it has nothing
+    pub fn read(path: &Path) -> Option<Self> {
+        let mut meta = Meta::default();
+
+        if let Ok(exif) =
+            exif::Reader::new().read_from_container(&mut BufReader::new(File::open(path).ok()?))
+        {
+            meta.merge_exif(&exif);
+        } else if let Some(format) = raw::format_name(path) {
+            // raws that arent quite tiff usually copy their exif into the preview
+            if let Some(exif) = raw::Preview::read(path).ok().and_then(|preview| {
+                exif::Reader::new()
+                    .read_from_container(&mut Cursor::new(preview.jpeg))
+                    .ok()
+            }) {
+                meta.merge_exif(&exif);
+            }
+
+            meta.raw = Some(format.to_string());
+        }
+
+        if meta.raw.is_none() {
+            meta.raw = raw::format_name(path).map(str::to_string);
+        }
+
+        let mut head = vec![];
+        File::open(path)
+            .ok()?
+            .take(XMP_SCAN_BYTES)
+            .read_to_end(&mut head)
+            .ok()?;
+
+        // later sources only fill in what the earlier ones didnt have
+        if let Some(xmp) = Xmp::find(&head) {
+            meta.merge_xmp(&xmp);
+        }
+
+        if let Some(iptc) = Iptc::from_jpeg(&head) {
+            meta.merge_iptc(iptc);
+        }
+
+        (meta != Meta::default()).then_some(meta)
+    }
+
+    // Label and value of everything thats set, in the order the details panel shows them
+    pub fn rows(&self) -> Vec<(&'static str, String)> {
+        let mut rows = vec![];
+
+        let mut push = |label, value: &Option<String>| {
+            if let Some(value) = value {
+                rows.push((label, value.clone()));
+            }
+        };
+
+        push("Title", &self.title);
+        push("Artist", &self.artist);
+        push("Album", &self.album);
+        push("Duration", &self.duration.map(audio::format_duration));
+        push("Description", &self.description);
+        push("Taken", &self.taken_at);
+        push("Camera", &self.camera);
+        push("Raw", &self.raw);
+        push("Lens", &self.lens);
+        push("Exposure", &self.exposure);
+        push("Aperture", &self.aperture);
+        push("ISO", &self.iso.map(|iso| iso.to_string()));
+        push("Focal length", &self.focal_length);
+        push("Creator", &self.creator);
+        push("Copyright", &self.copyright);
+        push("Software", &self.software);
+
+        if !self.keywords.is_empty() {
+            rows.push(("Keywords", self.keywords.join(", ")));
+        }
+
+        if let Some(rating) = self.rating {
+            rows.push(("Rating", "★".repeat(rating as usize)));
+        }
+
+        rows
+    }
+
+    fn merge_exif(&mut self, exif: &Exif) {
+        let string = |tag| {
+            let field = exif.get_field(tag, In::PRIMARY)?;
+
+            match &field.value {
+                Value::Ascii(parts) => parts
+                    .iter()
+                    .map(|part| String::from_utf8_lossy(part).trim().to_string())
+                    .find(|part| !part.is_empty()),
+                _ => Some(field.display_value().to_string()),
+            }
+        };
+
+        let with_unit = |tag| {
+            exif.get_field(tag, In::PRIMARY)
+                .map(|field| field.display_value().with_unit(exif).to_string())
+        };
+
+        let rational = |tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(values) => Some(
+                values
+                    .iter()
+                    .map(|value| value.to_f64())
+                    .collect::<Vec<_>>(),
+            ),
+            _ => None,
+        };
+
+        self.camera = match (string(Tag::Make), string(Tag::Model)) {
+            // most models already start with the make
+            (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.or(model),
+        };
+
+        self.lens = string(Tag::LensModel);
+        self.exposure = with_unit(Tag::ExposureTime);
+        self.aperture = rational(Tag::FNumber)
+            .and_then(|values| values.first().copied())
+            .map(|f| format!("f/{}", f));
+        self.iso = exif
+            .get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0));
+        self.focal_length = with_unit(Tag::FocalLength);
+        // exif dates are "2024:05:01 12:34:56", the colons in the date part just read oddly
+        self.taken_at = string(Tag::DateTimeOriginal)
+            .or_else(|| string(Tag::DateTime))
+            .map(|taken_at| taken_at.replacen(':', "-", 2));
+        self.orientation = exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .map(|orientation| orientation as u16);
+        self.software = string(Tag::Software);
+        self.description = string(Tag::ImageDescription);
+        self.creator = string(Tag::Artist);
+        self.copyright = string(Tag::Copyright);
+
+        // degrees, minutes, seconds, with the hemisphere in a separate ref tag
+        let coordinate = |tag, reference, negative: &str| {
+            let values = rational(tag)?;
+            let degrees = values.first()?
+                + values.get(1).unwrap_or(&0.0) / 60.0
+                + values.get(2).unwrap_or(&0.0) / 3600.0;
+
+            Some(if string(reference).as_deref() == Some(negative) {
+                -degrees
+            } else {
+                degrees
+            })
+        };
+
+        if let (Some(latitude), Some(longitude)) = (
+            coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+            coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+        ) {
+            let below_sea = exif
+                .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+                == Some(1);
+
+            self.gps = Some(Gps {
+                latitude,
+                longitude,
+                altitude: rational(Tag::GPSAltitude)
+                    .and_then(|values| values.first().copied())
+                    .map(|altitude| if below_sea { -altitude } else { altitude }),
+            });
+        }
+    }
+
+    fn merge_xmp(&mut self, xmp: &Xmp) {
+        self.title = self.title.take().or_else(|| xmp.alt(&TITLE));
+        self.description = self.description.take().or_else(|| xmp.alt(&DESCRIPTION));
+        self.creator = self
+            .creator
+            .take()
+            .or_else(|| xmp.list(&CREATOR).into_iter().next());
+        self.copyright = self.copyright.take().or_else(|| xmp.alt(&RIGHTS));
+        self.rating = self.rating.or_else(|| {
+            xmp.property(&RATING)?
+                .parse::<f64>()
+                .ok()
+                .map(|rating| rating.clamp(0.0, 5.0) as u8)
+        });
+        self.lens = self.lens.take().or_else(|| xmp.property(&LENS));
+        self.keywords.extend(xmp.list(&SUBJECT));
+    }
+
+    fn merge_iptc(&mut self, iptc: Iptc) {
+        self.title = self.title.take().or(iptc.title);
+        self.description = self.description.take().or(iptc.caption);
+        self.creator = self.creator.take().or(iptc.byline);
+        self.copyright = self.copyright.take().or(iptc.copyright);
+
+        for keyword in iptc.keywords {
+            if !self.keywords.contains(&keyword) {
+                self.keywords.push(keyword);
+            }
+        }
+    }
+}
+
+// The raw xmp packet, which is rdf/xml. only a handful of properties are needed, so this
+// picks them out with patterns instead of pulling in an xml parser
+struct Xmp(String);
+
+// a simple property, written either as an attribute or as an element
+fn property_pattern(name: &str) -> Regex {
+    let name = regex::escape(name);
+    Regex::new(&format!(r#"(?s){name}="([^"]*)"|<{name}>([^<]*)</{name}>"#)).unwrap()
+}
+
+// a bag, seq or alt property, the items are picked out of it with RDF_LI
+fn list_pattern(name: &str) -> Regex {
+    let name = regex::escape(name);
+    Regex::new(&format!(r"(?s)<{name}>(.*?)</{name}>")).unwrap()
+}
+
+lazy_static::lazy_static! {
+    static ref RDF_LI: Regex = Regex::new(r"(?s)<rdf:li[^>]*>(.*?)</rdf:li>").unwrap();
+    static ref TITLE: Regex = list_pattern("dc:title");
+    static ref DESCRIPTION: Regex = list_pattern("dc:description");
+    static ref CREATOR: Regex = list_pattern("dc:creator");
+    static ref RIGHTS: Regex = list_pattern("dc:rights");
+    static ref SUBJECT: Regex = list_pattern("dc:subject");
+    static ref RATING: Regex = property_pattern("xmp:Rating");
+    static ref LENS: Regex = property_pattern("aux:Lens");
+}
+
+impl Xmp {
+    fn find(bytes: &[u8]) -> Option<Self> {
+        let start = find_bytes(bytes, b"<x:xmpmeta")?;
+        let end = find_bytes(&bytes[start..], b"</x:xmpmeta>")? + start;
+
+        Some(Xmp(String::from_utf8_lossy(&bytes[start..end]).to_string()))
+    }
+
+    // takes a property_pattern
+    fn property(&self, pattern: &Regex) -> Option<String> {
+        let captures = pattern.captures(&self.0)?;
+
+        captures
+            .get(1)
+            .or(captures.get(2))
+            .map(|value| unescape(value.as_str().trim()))
+            .filter(|value| !value.is_empty())
+    }
+
+    // every item of a bag or seq property, takes a list_pattern
+    fn list(&self, pattern: &Regex) -> Vec<String> {
+        pattern
+            .captures(&self.0)
+            .map(|captures| {
+                RDF_LI
+                    .captures_iter(&captures[1])
+                    .map(|item| unescape(item[1].trim()))
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // language alternatives, the first one is the default language
+    fn alt(&self, pattern: &Regex) -> Option<String> {
+        self.list(pattern).into_iter().next()
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// The IPTC-IIM application record, which jpegs carry in a photoshop APP13 segment
+#[derive(Default)]
+struct Iptc {
+    title: Option<String>,
+    keywords: Vec<String>,
+    byline: Option<String>,
+    copyright: Option<String>,
+    caption: Option<String>,
+}
+
+impl Iptc {
+    fn from_jpeg(bytes: &[u8]) -> Option<Self> {
+        if !bytes.starts_with(&[0xff, 0xd8]) {
+            return None;
+        }
+
+        // walk the segments up to the image data
+        let mut i = 2;
+        while i + 4 <= bytes.len() && bytes[i] == 0xff {
+            let marker = bytes[i + 1];
+            let length = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+            let segment = bytes.get(i + 4..i + 2 + length)?;
+
+            if marker == 0xda {
+                break;
+            }
+
+            if marker == 0xed {
+                if let Some(data) = segment.strip_prefix(b"Photoshop 3.0\0") {
+                    return Self::from_resources(data);
+                }
+            }
+
+            i += 2 + length;
+        }
+
+        None
+    }
+
+    // photoshop image resources, iptc is resource 0x0404
+    fn from_resources(mut data: &[u8]) -> Option<Self> {
+        while data.len() >= 12 && data.starts_with(b"8BIM") {
+            let id = u16::from_be_bytes([data[4], data[5]]);
+
+            // pascal string name, padded to an even length including the length byte
+            let name_length = data[6] as usize;
+            let mut offset = 7 + name_length;
+            offset += offset % 2;
+
+            let size = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+            let body = data.get(offset + 4..offset + 4 + size)?;
+
+            if id == 0x0404 {
+                return Some(Self::from_records(body));
+            }
+
+            data = data.get(offset + 4 + size + size % 2..)?;
+        }
+
+        None
+    }
+
+    fn from_records(mut data: &[u8]) -> Self {
+        let mut iptc = Iptc::default();
+
+        // 0x1c, record number, dataset number, 2 byte length, value
+        while data.len() >= 5 && data[0] == 0x1c {
+            let (record, dataset) = (data[1], data[2]);
+            let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+
+            let Some(value) = data.get(5..5 + length) else {
+                break;
+            };
+            let value = String::from_utf8_lossy(value).trim().to_string();
+
+            if record == 2 && !value.is_empty() {
+                match dataset {
+                    5 => iptc.title = Some(value),
+                    25 => iptc.keywords.push(value),
+                    80 => iptc.byline = Some(value),
+                    116 => iptc.copyright = Some(value),
+                    120 => iptc.caption = Some(value),
+                    _ => {}
+                }
+            }
+
+            data = &data[5 + length..];
+        }
+
+        iptc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a jpeg made of just the given segments, each a marker and its payload
+    fn jpeg(segments: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8];
+        for (marker, payload) in segments {
+            bytes.extend([0xff, *marker]);
+            bytes.extend((payload.len() as u16 + 2).to_be_bytes());
+            bytes.extend(payload);
+        }
+        bytes.extend([0xff, 0xd9]);
+        bytes
+    }
+
+    fn app13(resources: &[Vec<u8>]) -> (u8, Vec<u8>) {
+        let mut payload = b"Photoshop 3.0\0".to_vec();
+        for resource in resources {
+            payload.extend(resource);
+        }
+        (0xed, payload)
+    }
+
+    // an 8BIM block with its pascal string name and body both padded to even lengths
+    fn resource(id: u16, name: &str, body: &[u8]) -> Vec<u8> {
+        let mut bytes = b"8BIM".to_vec();
+        bytes.extend(id.to_be_bytes());
+        bytes.push(name.len() as u8);
+        bytes.extend(name.as_bytes());
+        if (1 + name.len()) % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes.extend((body.len() as u32).to_be_bytes());
+        bytes.extend(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn dataset(number: u8, value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x1c, 2, number];
+        bytes.extend((value.len() as u16).to_be_bytes());
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    fn records() -> Vec<u8> {
+        [
+            dataset(5, "Harbour"),
+            dataset(25, "boats"),
+            dataset(25, "evening"),
+            dataset(80, "Ann Example"),
+            dataset(116, "(c) Ann Example"),
+            dataset(120, "Boats coming in"),
+        ]
+        .concat()
+    }
+
+    const PACKET: &str = r#"<?xpacket begin=""?><x:xmpmeta xmlns:x="adobe:ns:meta/">
+        <rdf:RDF><rdf:Description xmp:Rating="4">
+        <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Harbour</rdf:li><rdf:li xml:lang="de">Hafen</rdf:li></rdf:Alt></dc:title>
+        <dc:subject><rdf:Bag><rdf:li>boats</rdf:li><rdf:li> </rdf:li><rdf:li>salt &amp; sea</rdf:li></rdf:Bag></dc:subject>
+        <aux:Lens>50mm &quot;nifty&quot;</aux:Lens>
+        </rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end="w"?>"#;
+
+    fn xmp_jpeg() -> Vec<u8> {
+        let mut payload = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        payload.extend(PACKET.as_bytes());
+        jpeg(&[(0xe0, b"JFIF\0\x01\x02".to_vec()), (0xe1, payload)])
+    }
+
+    #[test]
+    fn xmp_in_app1() {
+        let xmp = Xmp::find(&xmp_jpeg()).unwrap();
+
+        assert_eq!(xmp.alt(&TITLE).as_deref(), Some("Harbour"));
+        assert_eq!(xmp.list(&SUBJECT), ["boats", "salt & sea"]);
+        assert_eq!(xmp.property(&RATING).as_deref(), Some("4"));
+        assert_eq!(xmp.property(&LENS).as_deref(), Some("50mm \"nifty\""));
+        assert_eq!(xmp.alt(&CREATOR), None);
+    }
+
+    #[test]
+    fn xmp_cut_short() {
+        let bytes = xmp_jpeg();
+        let end = find_bytes(&bytes, b"</x:xmpmeta>").unwrap();
+
+        assert!(Xmp::find(&bytes[..end + 5]).is_none());
+        assert!(Xmp::find(b"").is_none());
+        assert!(Xmp::find(b"</x:xmpmeta><x:xmpmeta").is_none());
+    }
+
+    #[test]
+    fn iptc_in_app13() {
+        let bytes = jpeg(&[
+            (0xe0, b"JFIF\0\x01\x02".to_vec()),
+            app13(&[
+                resource(0x03ed, "", &[0; 16]),
+                resource(0x0404, "", &records()),
+            ]),
+        ]);
+        let iptc = Iptc::from_jpeg(&bytes).unwrap();
+
+        assert_eq!(iptc.title.as_deref(), Some("Harbour"));
+        assert_eq!(iptc.keywords, ["boats", "evening"]);
+        assert_eq!(iptc.byline.as_deref(), Some("Ann Example"));
+        assert_eq!(iptc.copyright.as_deref(), Some("(c) Ann Example"));
+        assert_eq!(iptc.caption.as_deref(), Some("Boats coming in"));
+    }
+
+    #[test]
+    fn resource_padding() {
+        // names of every parity in front, some with odd sized bodies that get a pad byte
+        for name in ["", "a", "ab", "abc"] {
+            for size in [0, 1, 2, 3] {
+                let data = [
+                    resource(0x03ed, name, &vec![0x1c; size]),
+                    resource(0x0404, name, &records()),
+                ]
+                .concat();
+                let iptc = Iptc::from_resources(&data).unwrap();
+
+                assert_eq!(
+                    iptc.title.as_deref(),
+                    Some("Harbour"),
+                    "{:?} {}",
+                    name,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_iptc() {
+        // only the segments before the image data count
+        let mut bytes = jpeg(&[(0xda, vec![0; 4])]);
+        let tail = jpeg(&[app13(&[resource(0x0404, "", &records())])]);
+        bytes.extend(&tail[2..]);
+
+        assert!(Iptc::from_jpeg(&bytes).is_none());
+        assert!(Iptc::from_jpeg(&jpeg(&[app13(&[resource(0x03ed, "", &[1, 2])])])).is_none());
+        assert!(Iptc::from_jpeg(&jpeg(&[(0xed, b"Photoshop 2.5\0".to_vec())])).is_none());
+        assert!(Iptc::from_jpeg(b"\x89PNG\r\n\x1a\n").is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = jpeg(&[
+            (0xe0, b"JFIF\0\x01\x02".to_vec()),
+            app13(&[
+                resource(0x03ed, "abc", &[0; 5]),
+                resource(0x0404, "a", &records()),
+            ]),
+        ]);
+
+        for end in 0..bytes.len() {
+            Iptc::from_jpeg(&bytes[..end]);
+        }
+
+        let bytes = xmp_jpeg();
+        for end in 0..bytes.len() {
+            Xmp::find(&bytes[..end]);
+        }
+
+        // records cut short keep the ones that made it
+        let records = records();
+        let iptc = Iptc::from_records(&records[..records.len() - 1]);
+        assert_eq!(iptc.keywords, ["boats", "evening"]);
+        assert_eq!(iptc.caption, None);
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        // a segment running past the end of the file
+        let mut bytes = jpeg(&[app13(&[resource(0x0404, "", &records())])]);
+        bytes[4..6].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert!(Iptc::from_jpeg(&bytes).is_none());
+
+        // segment lengths too short to cover their own length field
+        for length in [0u16, 1] {
+            let mut bytes = jpeg(&[(0xe0, vec![])]);
+            bytes[4..6].copy_from_slice(&length.to_be_bytes());
+            assert!(Iptc::from_jpeg(&bytes).is_none());
+        }
+
+        // a resource body and a name running past the end of the resources
+        let mut data = resource(0x0404, "", &records());
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Iptc::from_resources(&data).is_none());
+
+        let mut data = resource(0x0404, "", &records());
+        data[6] = 0xff;
+        assert!(Iptc::from_resources(&data).is_none());
+
+        // a dataset longer than whats left
+        let mut records = records();
+        records[3..5].copy_from_slice(&0xffffu16.to_be_bytes());
+        let iptc = Iptc::from_records(&records);
+        assert_eq!(iptc.title, None);
+        assert!(iptc.keywords.is_empty());
+    }
+}
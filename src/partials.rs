@@ -1,8 +1,19 @@
-use std::{fs::Metadata, os::linux::fs::MetadataExt, path::PathBuf};
+use std::path::{Path, PathBuf};
 
-use maud::{html, Markup, DOCTYPE};
+use maud::{html, Markup, PreEscaped, DOCTYPE};
 
-use crate::{Args, PWD};
+use crate::{
+    archive, audio, convert,
+    db::{rel_path, FileRecord},
+    listing::{ListingQuery, Page, Show, SortKey, SortOrder},
+    meta::Meta,
+    raw,
+    reader::{ReadMode, Reading},
+    search::SearchMode,
+    text::{self, Text},
+    thumbs::{thumb_url, ThumbSize},
+    Args, PWD,
+};
 
 pub fn header(page_title: &str) -> Markup {
     html! {
@@ -25,30 +36,55 @@ pub struct FooterArgs {
     pub num_entries: usize,
     pub num_dirs: usize,
     pub total_size: u64,
+    // (current, total) when viewing a single file
+    pub position: Option<(usize, usize)>,
+    // bytes that could be freed, on the duplicates report
+    pub reclaimable: Option<u64>,
 }
 
-pub fn footer(args: FooterArgs) -> Markup {
+impl FooterArgs {
+    pub fn from_entries(entries: &[FileRecord]) -> Self {
+        FooterArgs {
+            num_entries: entries.len(),
+            num_dirs: entries.iter().filter(|record| record.is_dir).count(),
+            total_size: entries
+                .iter()
+                .filter(|record| !record.is_dir)
+                .map(|record| record.size)
+                .sum(),
+            position: None,
+            reclaimable: None,
+        }
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
     let sizes = ["B", "KB", "MB", "GB", "TB"];
 
-    let mut size = args.total_size as f64;
+    let mut size = bytes as f64;
     let mut i = 0;
-    while size >= 1024.0 && i < sizes.len() {
+    while size >= 1024.0 && i < sizes.len() - 1 {
         size /= 1024.0;
         i += 1;
     }
 
-    let size_str = {
-        if i == 0 {
-            format!("{} {}", size as u64, sizes[i])
-        } else {
-            format!("{:.2} {}", size, sizes[i])
-        }
-    };
+    if i == 0 {
+        format!("{} {}", size as u64, sizes[i])
+    } else {
+        format!("{:.2} {}", size, sizes[i])
+    }
+}
+
+pub fn footer(args: FooterArgs) -> Markup {
+    let size_str = format_size(args.total_size);
 
     html! {
         footer {
             div class="info" {
                 p class="entries" {
+                    @if let Some((current, total)) = args.position {
+                        (format!("{} / {} | ", current, total))
+                    }
                     (args.num_entries)
                     " entries (";
                     (args.num_dirs)
@@ -57,6 +93,9 @@ pub fn footer(args: FooterArgs) -> Markup {
                         if args.num_dirs == 1 { "), " } else { "s), " }
                     )
                     (size_str)
+                    @if let Some(reclaimable) = args.reclaimable {
+                        (format!(", {} reclaimable", format_size(reclaimable)))
+                    }
                 }
                 p class="version" {
                     (format!("iv~~ v{}", VERSION))
@@ -66,7 +105,7 @@ pub fn footer(args: FooterArgs) -> Markup {
     }
 }
 
-pub fn breadcrumb(uri_path: &str, path: &PathBuf) -> Markup {
+pub fn breadcrumb(query: &ListingQuery, uri_path: &str, path: &Path) -> Markup {
     let pwd = PWD.read().unwrap();
 
     let rel_path = path
@@ -81,9 +120,19 @@ pub fn breadcrumb(uri_path: &str, path: &PathBuf) -> Markup {
         .filter(|s| !s.is_empty())
         .collect::<Vec<_>>();
 
-    let mut hrefs = vec![String::from("/")];
+    let query_string = query.without_search().query_string();
+
+    let mut hrefs = vec![format!("/{}", query_string)];
     for i in 1..parts.len() {
-        hrefs.push(format!("/{}", parts[1..=i].join("/")));
+        hrefs.push(format!(
+            "/{}{}",
+            parts[1..=i]
+                .iter()
+                .map(|part| urlencoding::encode(part))
+                .collect::<Vec<_>>()
+                .join("/"),
+            query_string
+        ));
     }
 
     html! {
@@ -100,10 +149,114 @@ pub fn breadcrumb(uri_path: &str, path: &PathBuf) -> Markup {
     }
 }
 
+pub fn search_box(query: &ListingQuery) -> Markup {
+    let mode = query.mode.unwrap_or_default();
+
+    html! {
+        form class="search" action="/!search" method="get" {
+            @for (key, value) in query.without_search().pairs() {
+                input type="hidden" name=(key) value=(value);
+            }
+            input
+            type="search"
+            name="q"
+            placeholder="search"
+            value=(query.q.clone().unwrap_or_default());
+            select name="mode" {
+                @for option in SearchMode::ALL {
+                    option value=(option.as_str()) selected[option == mode] { (option.as_str()) }
+                }
+            }
+        }
+    }
+}
+
+pub fn filter_controls(args: &Args, query: &ListingQuery) -> Markup {
+    let shown = query.shown();
+    let recursive = query.recursive(args);
+
+    html! {
+        div class="filters" {
+            // theres nothing below the root to flatten without traversal, and search is always flat
+            @if args.traverse && query.q.is_none() {
+                a
+                class=(if recursive { "chip active" } else { "chip" })
+                href=(query.with_recursive(!recursive).query_string())
+                title=(format!("Show every file up to {} levels down", query.depth(args))) {
+                    "flatten"
+                }
+            }
+            @for show in Show::ALL {
+                a
+                class=(if shown.contains(&show) { "chip active" } else { "chip" })
+                href=(query.with_show_toggled(show).query_string()) {
+                    (show.as_str())
+                }
+            }
+            @let min_rating = query.min_rating();
+            span class="min-rating" title="Only show files rated at least this much" {
+                @for stars in 1..=5u8 {
+                    // clicking the current minimum clears it
+                    a href=(query.with_min_rating(if stars == min_rating { 0 } else { stars }).query_string()) {
+                        (icon(if stars <= min_rating { "star" } else { "star_border" }, 16))
+                    }
+                }
+            }
+            form class="glob" method="get" {
+                // carry over everything else, the input below replaces the glob
+                @for (key, value) in query.first_page().pairs() {
+                    @if key != "glob" {
+                        input type="hidden" name=(key) value=(value);
+                    }
+                }
+                input
+                type="text"
+                name="glob"
+                placeholder="*.webp"
+                value=(query.glob.clone().unwrap_or_default());
+            }
+        }
+    }
+}
+
+pub fn sort_controls(args: &Args, query: &ListingQuery) -> Markup {
+    let current_key = query.sort_key(args);
+    let current_order = query.sort_order(args);
+
+    html! {
+        div class="sort" {
+            (icon("sort", 24))
+            @for key in SortKey::ALL {
+                @if key == current_key {
+                    // clicking the active key flips the order
+                    a
+                    class="active"
+                    href=(query.with_sort(key, current_order.reversed()).query_string())
+                    title=(format!("Sorted by {}, {}", key.as_str(), current_order.as_str())) {
+                        (key.as_str())
+                        (icon(if current_order == SortOrder::Asc { "arrow_upward" } else { "arrow_downward" }, 16))
+                    }
+                } @else {
+                    a href=(query.with_sort(key, key.default_order()).query_string()) { (key.as_str()) }
+                }
+            }
+            div class="vr" {}
+            @let collation = query.collation(args);
+            a
+            href=(query.with_collation(collation.toggled()).query_string())
+            title=(format!("Comparing names as {}, click for {}", collation.as_str(), collation.toggled().as_str())) {
+                (collation.as_str())
+            }
+        }
+    }
+}
+
 pub fn page(
+    args: &Args,
+    query: &ListingQuery,
     page_title: &str,
     uri_path: &str,
-    path: &PathBuf,
+    path: &Path,
     footer_args: FooterArgs,
     content: Markup,
 ) -> Markup {
@@ -114,17 +267,33 @@ pub fn page(
                 header class="header" {
                     h1 { (page_title) }
                     div class="vr" {}
-                    (breadcrumb(uri_path, path));
+                    (breadcrumb(query, uri_path, path));
+                    a class="header-link" href="/!tags" title="Tags" { (icon("label", 24)) }
+                    a class="header-link" href="/!favourites" title="Favourites" { (icon("favorite", 24)) }
+                    a class="header-link" href="/!albums" title="Albums" { (icon("photo_album", 24)) }
+                    a class="header-link" href="/!duplicates" title="Duplicates" { (icon("filter_none", 24)) }
+                    (search_box(query))
+                    (filter_controls(args, query))
+                    (sort_controls(args, query))
                 }
                 div class="content" {
                     (content)
                 }
                 (footer(footer_args))
             }
+            script src="/_!/send.js" {}
+            script src="/_!/tags.js" {}
+            script src="/_!/rating.js" {}
+            script src="/_!/albums.js" {}
         }
     }
 }
 
+// root relative, url encoded path of a file, for use in links
+pub fn url_path(path: &Path) -> String {
+    urlencoding::encode(&rel_path(path)).replace("%2F", "/")
+}
+
 pub fn icon(name: &str, size: usize) -> Markup {
     html! {
         i
@@ -134,28 +303,40 @@ pub fn icon(name: &str, size: usize) -> Markup {
     }
 }
 
-pub fn file_hash_id(meta: &Metadata) -> String {
-    let id = xorshift64(
-        (meta
-            .st_atime()
-            .wrapping_add(meta.st_mtime())
-            .wrapping_add(meta.st_ctime())) as u64,
-    );
+// Stands in for an image that couldnt be converted, so theres something better than a broken
+// icon in its place. heic without the heif feature ends up here too
+pub fn unsupported_image(mime: &str) -> Markup {
+    let format = mime
+        .rsplit('/')
+        .next()
+        .unwrap_or(mime)
+        .trim_start_matches("x-")
+        .to_uppercase();
 
-    let id = xorshift64(
-        id.wrapping_add(meta.st_size())
-            .wrapping_add(meta.st_ino() as u64),
-    );
+    html! {
+        svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 320 240" width="320" height="240" {
+            rect width="320" height="240" fill="#222" {}
+            text x="160" y="112" text-anchor="middle" fill="#aaa" font-family="sans-serif" font-size="18" {
+                (format!("{} can't be shown", format))
+            }
+            text x="160" y="140" text-anchor="middle" fill="#777" font-family="sans-serif" font-size="13" {
+                "download the original to open it"
+            }
+        }
+    }
+}
 
-    let id = xorshift64(
-        id.wrapping_add(meta.st_dev())
-            .wrapping_add(meta.st_mtime_nsec() as u64),
-    );
+pub fn file_hash_id(record: &FileRecord) -> String {
+    let id = xorshift64(record.mtime.wrapping_add(record.ctime) as u64);
+
+    let id = xorshift64(id.wrapping_add(record.size).wrapping_add(record.inode));
+
+    let id = xorshift64(id.wrapping_add(record.dev));
 
     format!("i{:0>16x}", id)
 }
 
-pub fn entry_grid_bg_stylesheet(entries: &Vec<(PathBuf, Metadata)>) -> Markup {
+pub fn entry_grid_bg_stylesheet(entries: &[FileRecord]) -> Markup {
     let mut stylesheet = vec![];
 
     stylesheet.push(String::from(
@@ -172,26 +353,15 @@ pub fn entry_grid_bg_stylesheet(entries: &Vec<(PathBuf, Metadata)>) -> Markup {
     ));
 
     for entry in entries {
-        let (path, meta) = entry;
-        let file_type = FileType::from(path);
-
-        if matches!(file_type, FileType::Image(_)) {
-            let id = file_hash_id(meta);
-
-            let path = path
-                .strip_prefix(PWD.read().unwrap().as_os_str())
-                .unwrap_or(&path)
-                .as_os_str()
-                .to_string_lossy()
-                .to_string();
-
-            let path = urlencoding::encode(&path);
+        if matches!(entry.file_type, FileType::Image(_)) {
+            let id = file_hash_id(entry);
 
             stylesheet.push(format!(
                 "#{}::before{{\
-                    background-image:url('/!_/{}');\
+                    background-image:url('{}');\
                 }}",
-                id, path
+                id,
+                thumb_url(ThumbSize::Small, entry)
             ));
         }
     }
@@ -201,25 +371,181 @@ pub fn entry_grid_bg_stylesheet(entries: &Vec<(PathBuf, Metadata)>) -> Markup {
     }
 }
 
-pub fn entry_grid(args: &Args, entries: Vec<(PathBuf, Metadata)>) -> Markup {
+// The cards of a grid without the grid itself, also what the grid script appends when paging.
+// show_dirs labels every card with the directory its in, for listings that span directories
+pub fn entry_cards(
+    args: &Args,
+    query: &ListingQuery,
+    entries: Vec<FileRecord>,
+    show_dirs: bool,
+) -> Markup {
     html! {
         (entry_grid_bg_stylesheet(&entries))
+        @for record in entries {
+            (entry(args, query, record, show_dirs))
+        }
+    }
+}
+
+pub fn entry_grid(
+    args: &Args,
+    query: &ListingQuery,
+    entries: Vec<FileRecord>,
+    show_dirs: bool,
+) -> Markup {
+    html! {
         div class="entry-grid" {
-            @for (path, meta) in entries {
-                (entry(args, path, meta))
+            (entry_cards(args, query, entries, show_dirs))
+        }
+    }
+}
+
+pub fn pager(query: &ListingQuery, page: &Page) -> Markup {
+    html! {
+        @if page.count > 1 {
+            (page_nav(query, page, true))
+            script src="/_!/grid.js" {}
+        }
+    }
+}
+
+// Plain links between pages, for pages grid.js cant append to because theyre more than one grid
+pub fn page_links(query: &ListingQuery, page: &Page) -> Markup {
+    html! {
+        @if page.count > 1 {
+            (page_nav(query, page, false))
+        }
+    }
+}
+
+fn page_nav(query: &ListingQuery, page: &Page, scroll: bool) -> Markup {
+    let next = page.next().filter(|_| scroll);
+
+    html! {
+        nav
+        class="pager"
+        data-next=[next.map(|next| query.with_page(next).query_string())]
+        {
+            @if let Some(prev) = page.prev() {
+                a href=(query.with_page(prev).query_string()) { (icon("chevron_left", 24)) }
+            }
+            span { (format!("page {} / {}", page.number, page.count)) }
+            @if let Some(next) = page.next() {
+                a href=(query.with_page(next).query_string()) { (icon("chevron_right", 24)) }
             }
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+// A single page of a directory listing, kept up to date with whats on disk by live.js.
+// flattened listings label cards with their directory instead, and dont live reload
+pub fn listing(
+    args: &Args,
+    query: &ListingQuery,
+    dir: &Path,
+    entries: Vec<FileRecord>,
+    page: &Page,
+    reading: Option<&Reading>,
+    readme: Option<(&Path, &str)>,
+) -> Markup {
+    let recursive = query.recursive(args);
+
+    // links out of a flattened listing remember which tree they came from, for the viewer
+    let card_query = if recursive {
+        query.with_tree(dir)
+    } else {
+        query.clone()
+    };
+
+    html! {
+        @if let Some((path, html)) = readme {
+            details class="readme" open {
+                summary {
+                    (icon("article", 16))
+                    a href=(format!("/!text/{}", url_path(path))) {
+                        (path.file_name().unwrap_or_default().to_string_lossy())
+                    }
+                }
+                (markdown(html))
+            }
+        }
+        // only there when the dir has images to read
+        @if let Some(reading) = reading {
+            div class="listing-actions" {
+                a
+                class="chip"
+                href=(format!("/!read/{}{}", url_path(dir), query.first_page().query_string()))
+                title=[reading.page.as_ref().map(|page| format!("Continue at {}", page))] {
+                    (icon("menu_book", 14))
+                    @if reading.page.is_some() { " continue reading" } @else { " read" }
+                }
+            }
+        }
+        (entry_grid(args, &card_query, entries, recursive))
+        (pager(query, page))
+        // archives dont change underneath us, or at least cant be watched for it
+        @if !recursive && dir.is_dir() {
+            div
+            class="live"
+            data-events=(format!("/!events/{}", url_path(dir)))
+            data-card=(format!("/!card/{}{}", url_path(dir), query.first_page().query_string()))
+            {}
+            script src="/_!/live.js" {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum FileType {
     Dir,
     Image(String),
     Video(String),
+    Audio(String),
     Unknown(String),
 }
 
+impl FileType {
+    // short name used when storing the type in the index
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FileType::Dir => "dir",
+            FileType::Image(_) => "image",
+            FileType::Video(_) => "video",
+            FileType::Audio(_) => "audio",
+            FileType::Unknown(_) => "unknown",
+        }
+    }
+
+    pub fn mime(&self) -> &str {
+        match self {
+            FileType::Dir => "inode/directory",
+            FileType::Image(mime)
+            | FileType::Video(mime)
+            | FileType::Audio(mime)
+            | FileType::Unknown(mime) => mime,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Self {
+        match mime.split('/').next().unwrap_or_default() {
+            "image" => FileType::Image(mime.to_string()),
+            "video" => FileType::Video(mime.to_string()),
+            "audio" => FileType::Audio(mime.to_string()),
+            _ => FileType::Unknown(mime.to_string()),
+        }
+    }
+
+    pub fn from_kind(kind: &str, mime: &str) -> Self {
+        match kind {
+            "dir" => FileType::Dir,
+            "image" => FileType::Image(mime.to_string()),
+            "video" => FileType::Video(mime.to_string()),
+            "audio" => FileType::Audio(mime.to_string()),
+            _ => FileType::Unknown(mime.to_string()),
+        }
+    }
+}
+
 impl From<&PathBuf> for FileType {
     fn from(path: &PathBuf) -> Self {
         if path.is_dir() {
@@ -237,16 +563,16 @@ impl From<&PathBuf> for FileType {
             "ts" | "d.ts" | "mts" | "d.mts" => {
                 return FileType::Unknown("text/typescript".to_string())
             }
+            "psd" => return FileType::Image("image/vnd.adobe.photoshop".to_string()),
             _ => {}
         }
 
-        let mime = actix_files::file_extension_to_mime(ext);
-
-        match mime.type_() {
-            mime::IMAGE => return FileType::Image(mime.to_string()),
-            mime::VIDEO => return FileType::Video(mime.to_string()),
-            _ => FileType::Unknown(mime.to_string()),
+        // raws are listed with all sorts of mimes or none, and usually in capitals
+        if let Some(mime) = raw::mime(ext) {
+            return FileType::Image(mime.to_string());
         }
+
+        FileType::from_mime(actix_files::file_extension_to_mime(ext).essence_str())
     }
 }
 
@@ -257,46 +583,97 @@ fn xorshift64(mut state: u64) -> u64 {
     state
 }
 
-pub fn entry(args: &Args, path: PathBuf, meta: Metadata) -> Markup {
-    let file_name = path.iter().last().unwrap().to_str().unwrap();
-    let file_type = FileType::from(&path);
+pub fn entry(args: &Args, query: &ListingQuery, record: FileRecord, show_dir: bool) -> Markup {
+    let file_name = record.name();
+    let id = file_hash_id(&record);
 
-    let pwd = PWD.read().unwrap();
+    // links out of the listing start at the first page of wherever they go
+    let query_string = query.first_page().query_string();
 
-    let path = path
-        .strip_prefix(pwd.as_os_str())
-        .unwrap_or(&path)
-        .as_os_str()
-        .to_string_lossy()
-        .to_string();
+    let dir = record.path.parent().map(rel_path).unwrap_or_default();
+    let dir_href = format!(
+        "/{}{}",
+        urlencoding::encode(&dir).replace("%2F", "/"),
+        query_string
+    );
 
-    let is_img = matches!(file_type, FileType::Image(_));
+    let path = rel_path(&record.path);
+    let is_img = matches!(record.file_type, FileType::Image(_));
 
     let path = urlencoding::encode(&path);
 
-    let id = file_hash_id(&meta);
-
     html! {
         div
         class=(if is_img { "entry img" } else { "entry" })
+        data-name=(file_name)
         {
-            @match file_type {
+            @match record.file_type.clone() {
                 FileType::Dir => {
                     a
                     class=(if args.traverse { "dir" } else { "dir disabled" })
-                    href=(format!("/{}", path.replace("%2F", "/"))) {
+                    href=(format!("/{}{}", path.replace("%2F", "/"), query_string)) {
                         (icon("folder", 96))
                         span class="name" { (file_name) }
                     }
                 }
                 FileType::Image(_) => {
                     div class="entry-img-inner" id=(id) {
-                        img src=(format!("/!_/{}", path));
+                        a href=(format!("/!view/{}{}", path.replace("%2F", "/"), query_string)) {
+                            img
+                            loading="lazy"
+                            src=(thumb_url(ThumbSize::Medium, &record))
+                            srcset=(format!("{} 2x", thumb_url(ThumbSize::Large, &record)));
+                        }
                     }
                 }
                 FileType::Video(mime) => {
                     video controls height="100%" width="100%" {
-                        source src=(format!("!_/{}", path)) type=(mime);
+                        source src=(format!("/!_/{}", path.replace("%2F", "/"))) type=(mime);
+                    }
+                }
+                FileType::Audio(mime) => {
+                    div class="entry-audio" {
+                        @if record.meta.as_ref().is_some_and(|meta| meta.cover) {
+                            img
+                            loading="lazy"
+                            src=(thumb_url(ThumbSize::Medium, &record))
+                            srcset=(format!("{} 2x", thumb_url(ThumbSize::Large, &record)));
+                        } @else {
+                            (icon("music_note", 96))
+                        }
+                        (audio_info(&record))
+                        audio controls preload="none" {
+                            source src=(format!("/!_/{}", path.replace("%2F", "/"))) type=(mime);
+                        }
+                    }
+                }
+                // zips open like dirs, except ones inside other zips
+                FileType::Unknown(_)
+                    if args.traverse && !record.archived && archive::is_archive_name(&record.path) =>
+                {
+                    a
+                    class="dir"
+                    href=(format!("/{}{}", path.replace("%2F", "/"), query_string)) {
+                        (icon("folder_zip", 96))
+                        span class="name" { (file_name) }
+                    }
+                    a
+                    class="read"
+                    href=(format!("/!read/{}{}", path.replace("%2F", "/"), query_string))
+                    title="Read" {
+                        (icon("menu_book", 20))
+                    }
+                }
+                FileType::Unknown(mime) if text::is_text(&mime) => {
+                    a
+                    class="text"
+                    href=(format!("/!text/{}{}", path.replace("%2F", "/"), query_string)) {
+                        @if let Some(snippet) = &record.snippet {
+                            pre class="snippet" { (snippet) }
+                        } @else {
+                            (icon("description", 96))
+                        }
+                        span class="name" { (file_name) }
                     }
                 }
                 FileType::Unknown(mime) => {
@@ -309,6 +686,561 @@ pub fn entry(args: &Args, path: PathBuf, meta: Metadata) -> Markup {
                     }
                 }
             }
+            @if !record.is_dir && !record.archived {
+                div class="entry-meta" {
+                    (tag_editor(&record))
+                    (album_editor(&record))
+                    @if is_img {
+                        (similar_link(&record))
+                    }
+                    (rating_editor(&record))
+                }
+            }
+            @if is_img {
+                (meta_summary(&record))
+            }
+            @if show_dir {
+                p class="entry-dir" {
+                    a href=(dir_href) { "/" (dir) }
+                }
+            }
+        }
+    }
+}
+
+pub fn search_results(
+    args: &Args,
+    query: &ListingQuery,
+    results: Vec<FileRecord>,
+    page: &Page,
+    error: Option<String>,
+) -> Markup {
+    html! {
+        @if let Some(error) = error {
+            p class="notice" { "Invalid search: " (error) }
+        } @else if query.q.as_ref().is_none_or(|q| q.is_empty()) {
+            p class="notice" { "Type something to search for" }
+        } @else if results.is_empty() {
+            p class="notice" { "Nothing found" }
+        }
+        (entry_grid(args, &query.without_search(), results, true))
+        (pager(query, page))
+    }
+}
+
+pub fn viewer(query: &ListingQuery, images: &[FileRecord], position: usize) -> Markup {
+    let image = &images[position];
+    let query_string = query.first_page().query_string();
+
+    let view_href =
+        |record: &FileRecord| format!("/!view/{}{}", url_path(&record.path), query_string);
+
+    let prev = position.checked_sub(1).map(|i| &images[i]);
+    let next = images.get(position + 1);
+
+    // back to the flattened listing it was opened from, or the dir its in
+    let up_dir = query
+        .tree
+        .as_ref()
+        .map_or(image.path.parent().unwrap(), Path::new);
+
+    let up = format!("/{}{}", url_path(up_dir), query_string);
+
+    html! {
+        div
+        class="viewer"
+        data-prev=[prev.map(view_href)]
+        data-next=[next.map(view_href)]
+        data-prev-src=[prev.map(convert::image_url)]
+        data-next-src=[next.map(convert::image_url)]
+        data-first=(view_href(&images[0]))
+        data-last=(view_href(&images[images.len() - 1]))
+        data-up=(up)
+        {
+            @if let Some(prev) = prev {
+                a class="nav prev" href=(view_href(prev)) title="Previous" { (icon("chevron_left", 64)) }
+            } @else {
+                span class="nav prev" {}
+            }
+            img src=(convert::image_url(image)) alt=(image.name());
+            @if let Some(next) = next {
+                a class="nav next" href=(view_href(next)) title="Next" { (icon("chevron_right", 64)) }
+            } @else {
+                span class="nav next" {}
+            }
+            p class="name" {
+                (image.name())
+                // whats shown is a converted copy, so offer the real thing too
+                @if !convert::browser_native(image.file_type.mime()) {
+                    a
+                    class="download"
+                    href=(format!("/!_/{}", url_path(&image.path)))
+                    download=(image.name())
+                    title="Download original" {
+                        (icon("download", 18))
+                    }
+                }
+            }
+            (meta_panel(image))
+            @if !image.archived {
+                div class="entry-meta" {
+                    (tag_editor(image))
+                    (album_editor(image))
+                    (similar_link(image))
+                    (rating_editor(image))
+                }
+            }
+        }
+        script src="/_!/viewer.js" {}
+    }
+}
+
+// Pages of a dir or archive one or two at a time, or all in one long strip. reader.js does the
+// paging and tells the server where it got to
+pub fn reader(query: &ListingQuery, dir: &Path, pages: &[FileRecord], reading: &Reading) -> Markup {
+    let up = format!("/{}{}", url_path(dir), query.first_page().query_string());
+
+    html! {
+        div
+        class="reader"
+        data-url=(format!("/!read/{}", url_path(dir)))
+        data-mode=(reading.mode.as_str())
+        data-rtl=(reading.rtl)
+        data-page=(reading.position(pages))
+        data-up=(up)
+        {
+            div class="reader-bar" {
+                a class="chip" href=(up) title="Back to the listing" { (icon("arrow_back", 14)) }
+                @for mode in ReadMode::ALL {
+                    button
+                    class=(if mode == reading.mode { "chip active" } else { "chip" })
+                    data-mode=(mode.as_str()) {
+                        (mode.as_str())
+                    }
+                }
+                button
+                class=(if reading.rtl { "chip rtl active" } else { "chip rtl" })
+                title="Right to left, for manga" {
+                    "rtl"
+                }
+                span class="reader-position" {}
+            }
+            div class="reader-pages" {
+                @for page in pages {
+                    img
+                    class="reader-page"
+                    loading="lazy"
+                    src=(convert::image_url(page))
+                    alt=(page.name())
+                    width=[page.width]
+                    height=[page.height]
+                    data-name=(page.name())
+                    // double page scans get a spread to themselves
+                    data-wide=[page.width.zip(page.height).filter(|(width, height)| width > height).map(|_| "")];
+                }
+            }
+        }
+        script src="/_!/reader.js" {}
+    }
+}
+
+// A text file with a line about what it is, content is the file itself (see code and markdown)
+pub fn text_preview(
+    query: &ListingQuery,
+    record: &FileRecord,
+    text: &Text,
+    syntax: &str,
+    content: Markup,
+) -> Markup {
+    let up = format!(
+        "/{}{}",
+        url_path(record.path.parent().unwrap()),
+        query.first_page().query_string()
+    );
+
+    html! {
+        div class="text-preview" {
+            div class="text-info" {
+                a class="chip" href=(up) title="Back to the listing" { (icon("arrow_back", 14)) }
+                span class="name" { (record.name()) }
+                span { (syntax) }
+                span { (text.encoding) }
+                @let lines = text.text.lines().count();
+                span { (lines) (if lines == 1 { " line" } else { " lines" }) }
+                span { (format_size(record.size)) }
+                a
+                class="download"
+                href=(format!("/!_/{}", url_path(&record.path)))
+                download=(record.name())
+                title="Download" {
+                    (icon("download", 18))
+                }
+            }
+            @if text.truncated {
+                p class="notice" {
+                    "Only the first " (format_size(text::MAX_BYTES)) " of " (format_size(record.size)) " are shown"
+                }
+            }
+            (content)
+        }
+    }
+}
+
+// Highlighted lines of code, see text::highlight
+pub fn code(lines: &[String]) -> Markup {
+    html! {
+        pre class="code" {
+            @for (i, line) in lines.iter().enumerate() {
+                span class="line" id=(format!("L{}", i + 1)) { (PreEscaped(line)) }
+            }
+        }
+    }
+}
+
+// html thats been through markdown::render, which sanitizes it
+pub fn markdown(html: &str) -> Markup {
+    html! {
+        div class="markdown" { (PreEscaped(html)) }
+    }
+}
+
+pub fn tag_url(tag: &str) -> String {
+    format!("/!tags/{}", urlencoding::encode(tag))
+}
+
+// The tags of a file with buttons to change them, tags.js does the rest
+pub fn tag_editor(record: &FileRecord) -> Markup {
+    html! {
+        div class="tags" data-url=(format!("/!tag/{}", url_path(&record.path))) {
+            @for tag in &record.tags {
+                span class="tag" {
+                    a href=(tag_url(tag)) { (tag) }
+                    button class="tag-remove" data-tag=(tag) title="Remove tag" { "×" }
+                }
+            }
+            button class="tag-add" title="Add tag" { (icon("add", 16)) }
+        }
+    }
+}
+
+// Every tag in use, with buttons to rename or delete them everywhere
+pub fn tag_index(tags: &[(String, i64)]) -> Markup {
+    html! {
+        @if tags.is_empty() {
+            p class="notice" { "Nothing is tagged yet" }
+        }
+        div class="tag-index" {
+            @for (tag, count) in tags {
+                span class="tag" data-tag=(tag) {
+                    a href=(tag_url(tag)) { (tag) " (" (count) ")" }
+                    button class="tag-rename" title="Rename tag" { (icon("edit", 16)) }
+                    button class="tag-delete" title="Remove tag from every file" { (icon("close", 16)) }
+                }
+            }
+        }
+    }
+}
+
+pub fn tag_heading(tag: &str) -> Markup {
+    html! {
+        a href="/!tags" { "Tags" }
+        " / " (tag)
+    }
+}
+
+pub fn album_url(album: &str) -> String {
+    format!("/!albums/{}", urlencoding::encode(album))
+}
+
+pub fn album_heading(album: &str) -> Markup {
+    html! {
+        a href="/!albums" { "Albums" }
+        " / " (album) " "
+        a href=(format!("{}/export", album_url(album))) title="Paths of every file, one per line" { "(export)" }
+    }
+}
+
+// The albums a file is in, with buttons to add it to or take it out of one, see albums.js
+pub fn album_editor(record: &FileRecord) -> Markup {
+    html! {
+        div class="albums" data-url=(format!("/!album/{}", url_path(&record.path))) {
+            @for album in &record.albums {
+                span class="tag album" {
+                    a href=(album_url(album)) { (icon("photo_album", 12)) " " (album) }
+                    button class="album-remove" data-album=(album) title="Remove from album" { "×" }
+                }
+            }
+            button class="album-add" title="Add to album" { (icon("library_add", 16)) }
+        }
+    }
+}
+
+pub fn album_index(albums: &[(String, i64)]) -> Markup {
+    html! {
+        @if albums.is_empty() {
+            p class="notice" { "No albums yet, add files to one from their cards" }
+        }
+        div class="tag-index" {
+            @for (album, count) in albums {
+                span class="tag album" data-album=(album) {
+                    a href=(album_url(album)) { (album) " (" (count) ")" }
+                    a href=(format!("{}/export", album_url(album))) title="Export" { (icon("download", 16)) }
+                    button class="album-delete" title="Delete album" { (icon("close", 16)) }
+                }
+            }
+        }
+    }
+}
+
+// Everything known about an image, collapsible so it stays out of the way
+pub fn meta_panel(record: &FileRecord) -> Markup {
+    let rows = record.meta.as_ref().map(Meta::rows).unwrap_or_default();
+    let gps = record.meta.as_ref().and_then(|meta| meta.gps);
+
+    html! {
+        details class="meta-panel" {
+            summary title="Details" { (icon("info", 24)) }
+            dl {
+                @if let (Some(width), Some(height)) = (record.width, record.height) {
+                    dt { "Dimensions" }
+                    dd { (format!("{} × {}", width, height)) }
+                }
+                dt { "Size" }
+                dd { (format_size(record.size)) }
+                @for (label, value) in rows {
+                    dt { (label) }
+                    dd { (value) }
+                }
+                @if let Some(gps) = gps {
+                    dt { "Location" }
+                    dd {
+                        a
+                        href=(format!("https://www.openstreetmap.org/?mlat={0}&mlon={1}#map=15/{0}/{1}", gps.latitude, gps.longitude))
+                        target="_blank"
+                        rel="noreferrer" {
+                            (format!("{:.5}, {:.5}", gps.latitude, gps.longitude))
+                            @if let Some(altitude) = gps.altitude {
+                                (format!(", {:.0} m", altitude))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The few details that fit on a card, shown on hover
+pub fn meta_summary(record: &FileRecord) -> Markup {
+    let meta = record.meta.clone().unwrap_or_default();
+
+    let settings = [
+        meta.exposure.clone(),
+        meta.aperture.clone(),
+        meta.iso.map(|iso| format!("ISO {}", iso)),
+        meta.focal_length.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join("  ");
+
+    let lines = [
+        record
+            .width
+            .zip(record.height)
+            .map(|(width, height)| format!("{} × {}", width, height)),
+        meta.taken_at,
+        meta.camera,
+        (!settings.is_empty()).then_some(settings),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    html! {
+        @if !lines.is_empty() {
+            div class="entry-details" {
+                @for line in lines {
+                    span { (line) }
+                }
+            }
+        }
+    }
+}
+
+// Title, artist, album and length of an audio file, the file name if it isnt tagged
+pub fn audio_info(record: &FileRecord) -> Markup {
+    let meta = record.meta.clone().unwrap_or_default();
+
+    let by = [meta.artist, meta.album]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" — ");
+
+    html! {
+        div class="audio-info" {
+            span class="name" title=(record.name()) { (meta.title.unwrap_or(record.name())) }
+            @if !by.is_empty() {
+                span { (by) }
+            }
+            @if let Some(duration) = meta.duration {
+                span { (audio::format_duration(duration)) }
+            }
+        }
+    }
+}
+
+pub fn similar_link(record: &FileRecord) -> Markup {
+    html! {
+        a
+        class="similar"
+        href=(format!("/!similar/{}", url_path(&record.path)))
+        title="Find similar images" {
+            (icon("image_search", 16))
+        }
+    }
+}
+
+pub fn similar_heading(record: &FileRecord, distance: u32) -> Markup {
+    html! {
+        "Images like " (record.name())
+        (format!(", at most {} of 64 bits apart", distance))
+    }
+}
+
+// Groups of images that look alike, with links to loosen or tighten what counts as alike
+pub fn duplicates(
+    args: &Args,
+    query: &ListingQuery,
+    groups: Vec<Vec<FileRecord>>,
+    page: &Page,
+    distance: u32,
+    pending: usize,
+) -> Markup {
+    html! {
+        (duplicate_reports(false))
+        p class="notice" {
+            (format!("Images at most {} of 64 perceptual hash bits apart - ", distance))
+            a href=(query.with_distance(distance.saturating_sub(2)).query_string()) { "stricter" }
+            " / "
+            a href=(query.with_distance((distance + 2).min(64)).query_string()) { "looser" }
+        }
+        @if pending > 0 {
+            p class="notice" { (format!("Still indexing {} folders, reload for a complete report", pending)) }
+        }
+        @if groups.is_empty() {
+            p class="notice" { "No similar images found" }
+        }
+        @for group in groups {
+            p class="notice" { (group.len()) " similar images" }
+            (entry_grid(args, query, group, true))
+        }
+        (page_links(query, page))
+    }
+}
+
+// Switches between the similar and the exact duplicates report
+fn duplicate_reports(exact: bool) -> Markup {
+    html! {
+        p class="notice" {
+            a class=(if exact { "chip" } else { "chip active" }) href="/!duplicates" { "similar" }
+            " "
+            a class=(if exact { "chip active" } else { "chip" }) href="/!duplicates/exact" { "identical" }
+        }
+    }
+}
+
+pub struct DuplicateGroup {
+    pub records: Vec<FileRecord>,
+    // bytes freed by keeping only one copy, hardlinks dont count as copies
+    pub reclaimable: u64,
+}
+
+// Groups of byte identical files, biggest waste first
+pub fn exact_duplicates(
+    args: &Args,
+    query: &ListingQuery,
+    groups: Vec<DuplicateGroup>,
+    pending: usize,
+    hashing: bool,
+    unhashed: i64,
+) -> Markup {
+    let reclaimable: u64 = groups.iter().map(|group| group.reclaimable).sum();
+
+    html! {
+        (duplicate_reports(true))
+        p class="notice" {
+            (format!("{} reclaimable across {} sets of identical files", format_size(reclaimable), groups.len()))
+        }
+        @if pending > 0 {
+            p class="notice" { (format!("Still indexing {} folders, reload for a complete report", pending)) }
+        } @else if hashing && unhashed > 0 {
+            p class="notice" { (format!("Still hashing {} files of matching sizes, reload for a complete report", unhashed)) }
+        } @else if hashing {
+            p class="notice" { "Still hashing files of matching sizes, reload for a complete report" }
+        }
+        @for group in groups {
+            p class="notice" {
+                (format!(
+                    "{} copies of {}, {} reclaimable",
+                    group.records.len(),
+                    format_size(group.records[0].size),
+                    format_size(group.reclaimable)
+                ))
+            }
+            (entry_grid(args, query, group.records, true))
+        }
+    }
+}
+
+pub fn favourites_heading() -> Markup {
+    html! { "Favourites" }
+}
+
+// Files gathered from all over the root, labelled with where they are
+pub fn collection(
+    args: &Args,
+    query: &ListingQuery,
+    heading: Markup,
+    records: Vec<FileRecord>,
+    page: &Page,
+) -> Markup {
+    html! {
+        p class="notice" {
+            (heading)
+            @if records.is_empty() {
+                " - nothing here yet"
+            }
+        }
+        (entry_grid(args, query, records, true))
+        (pager(query, page))
+    }
+}
+
+// Stars and the favourite heart of a file, rating.js handles clicks and keyboard shortcuts
+pub fn rating_editor(record: &FileRecord) -> Markup {
+    html! {
+        div
+        class=(if record.rating > 0 || record.favourite { "rating set" } else { "rating" })
+        data-url=(format!("/!rate/{}", url_path(&record.path)))
+        data-rating=(record.rating)
+        data-favourite=(record.favourite)
+        {
+            button
+            class=(if record.favourite { "favourite active" } else { "favourite" })
+            title="Favourite (f)" {
+                (icon(if record.favourite { "favorite" } else { "favorite_border" }, 16))
+            }
+            @for stars in 1..=5u8 {
+                button
+                class="star"
+                data-stars=(stars)
+                title=(format!("{} star{} ({})", stars, if stars == 1 { "" } else { "s" }, stars)) {
+                    (icon(if stars <= record.rating { "star" } else { "star_border" }, 16))
+                }
+            }
         }
     }
 }
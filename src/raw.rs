@@ -0,0 +1,644 @@
+use std::{collections::HashSet, fs::File, os::unix::fs::FileExt, path::Path};
+
+use image::{metadata::Orientation, DynamicImage, ImageFormat};
+
+use crate::db::Error;
+
+// Camera raw formats whose preview can be pulled out without decoding the raw data.
+// all but raf are tiff underneath, cr3 and x3f arent and arent listed
+const FORMATS: [(&str, &str, &str); 17] = [
+    ("3fr", "image/x-hasselblad-3fr", "Hasselblad 3FR"),
+    ("arw", "image/x-sony-arw", "Sony ARW"),
+    ("cr2", "image/x-canon-cr2", "Canon CR2"),
+    ("dcr", "image/x-kodak-dcr", "Kodak DCR"),
+    ("dng", "image/x-adobe-dng", "Adobe DNG"),
+    ("erf", "image/x-epson-erf", "Epson ERF"),
+    ("iiq", "image/x-phaseone-iiq", "Phase One IIQ"),
+    ("kdc", "image/x-kodak-kdc", "Kodak KDC"),
+    ("mef", "image/x-mamiya-mef", "Mamiya MEF"),
+    ("nef", "image/x-nikon-nef", "Nikon NEF"),
+    ("nrw", "image/x-nikon-nrw", "Nikon NRW"),
+    ("orf", "image/x-olympus-orf", "Olympus ORF"),
+    ("pef", "image/x-pentax-pef", "Pentax PEF"),
+    ("raf", "image/x-fuji-raf", "Fujifilm RAF"),
+    ("rw2", "image/x-panasonic-rw2", "Panasonic RW2"),
+    ("sr2", "image/x-sony-sr2", "Sony SR2"),
+    ("srw", "image/x-samsung-srw", "Samsung SRW"),
+];
+
+// the ifds of a raw point at each other, a broken file shouldnt keep us walking forever
+const MAX_IFDS: usize = 64;
+
+// how far into an embedded jpeg its frame header is looked for
+const SOF_SCAN_BYTES: u64 = 256 * 1024;
+
+// tiff tags this cares about
+const COMPRESSION: u16 = 0x0103;
+const STRIP_OFFSETS: u16 = 0x0111;
+const ORIENTATION: u16 = 0x0112;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const SUB_IFDS: u16 = 0x014a;
+const JPEG_OFFSET: u16 = 0x0201;
+const JPEG_LENGTH: u16 = 0x0202;
+// panasonic keeps its full size preview in a tag of its own
+const RW2_JPEG: u16 = 0x002e;
+
+pub fn mime(ext: &str) -> Option<&'static str> {
+    FORMATS
+        .iter()
+        .find(|(known, _, _)| known.eq_ignore_ascii_case(ext))
+        .map(|(_, mime, _)| *mime)
+}
+
+pub fn is_raw(mime: &str) -> bool {
+    FORMATS.iter().any(|(_, known, _)| *known == mime)
+}
+
+// what the details panel calls the format, "Canon CR2" and so on
+pub fn format_name(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?;
+
+    FORMATS
+        .iter()
+        .find(|(known, _, _)| known.eq_ignore_ascii_case(ext))
+        .map(|(_, _, name)| *name)
+}
+
+// The biggest jpeg embedded in a raw file, and how the raw says it should be turned
+pub struct Preview {
+    pub jpeg: Vec<u8>,
+    pub orientation: Orientation,
+}
+
+impl Preview {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+
+        let mut magic = [0; 16];
+        file.read_exact_at(&mut magic, 0)?;
+
+        if &magic == b"FUJIFILMCCD-RAW " {
+            return Self::read_raf(&file);
+        }
+
+        let tiff = Tiff::new(&file)?;
+
+        let mut best: Option<(u64, u64, u64)> = None;
+        let mut orientation = Orientation::NoTransforms;
+
+        for (index, ifd) in tiff.ifds()?.iter().enumerate() {
+            // only the first ifd describes the image as a whole
+            if index == 0 {
+                if let Some(value) = ifd.value(&tiff, ORIENTATION) {
+                    orientation =
+                        Orientation::from_exif(value as u8).unwrap_or(Orientation::NoTransforms);
+                }
+            }
+
+            for (offset, length) in ifd.jpegs(&tiff) {
+                let Some(area) = jpeg_area(&file, offset) else {
+                    continue;
+                };
+
+                if best.is_none_or(|(best_area, _, _)| area > best_area) {
+                    best = Some((area, offset, length));
+                }
+            }
+        }
+
+        let (_, offset, length) = best.ok_or("no embedded jpeg preview")?;
+
+        Ok(Preview {
+            jpeg: read_at(&file, offset, length)?,
+            orientation,
+        })
+    }
+
+    // fuji isnt tiff, but its header says right where the jpeg is
+    fn read_raf(file: &File) -> Result<Self, Error> {
+        let mut header = [0; 8];
+        file.read_exact_at(&mut header, 84)?;
+
+        let offset = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let length = u32::from_be_bytes(header[4..].try_into().unwrap()) as u64;
+
+        jpeg_area(file, offset).ok_or("no embedded jpeg preview")?;
+
+        // the orientation is in the jpegs own exif, which decode reads
+        Ok(Preview {
+            jpeg: read_at(file, offset, length)?,
+            orientation: Orientation::NoTransforms,
+        })
+    }
+
+    // the preview as an image the right way up
+    pub fn decode(&self) -> Result<DynamicImage, Error> {
+        let mut img = image::load_from_memory_with_format(&self.jpeg, ImageFormat::Jpeg)?;
+
+        // previews usually have no exif of their own, but when they do it wins
+        let orientation = match exif_orientation(&self.jpeg) {
+            Some(orientation) if orientation != Orientation::NoTransforms => orientation,
+            _ => self.orientation,
+        };
+
+        img.apply_orientation(orientation);
+
+        Ok(img)
+    }
+
+    // The preview as a jpeg the browser will show the right way up. Previews that dont need
+    // turning are handed out as they are, the rest get turned and encoded again
+    pub fn browser_jpeg(self) -> Result<Vec<u8>, Error> {
+        if self.orientation == Orientation::NoTransforms {
+            return Ok(self.jpeg);
+        }
+
+        let img = self.decode()?;
+
+        let mut jpeg = vec![];
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode_image(&img.to_rgb8())?;
+
+        Ok(jpeg)
+    }
+}
+
+fn exif_orientation(jpeg: &[u8]) -> Option<Orientation> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(jpeg))
+        .ok()?;
+
+    let value = exif
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+
+    Orientation::from_exif(value as u8)
+}
+
+fn read_at(file: &File, offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+    if length == 0 || offset + length > file.metadata()?.len() {
+        return Err("embedded jpeg runs past the end of the file".into());
+    }
+
+    let mut buf = vec![0; length as usize];
+    file.read_exact_at(&mut buf, offset)?;
+
+    Ok(buf)
+}
+
+// Pixel count of the jpeg at offset, if it is one browsers and the image crate can decode.
+// raws also store the sensor data itself as lossless jpeg, which neither can
+fn jpeg_area(file: &File, offset: u64) -> Option<u64> {
+    let mut head = vec![0; SOF_SCAN_BYTES as usize];
+    let read = file.read_at(&mut head, offset).ok()?;
+    head.truncate(read);
+
+    if !head.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut at = 2;
+    while at + 9 < head.len() {
+        if head[at] != 0xff {
+            return None;
+        }
+
+        let marker = head[at + 1];
+        let length = u16::from_be_bytes([head[at + 2], head[at + 3]]) as usize;
+
+        // the length counts its own two bytes, anything less is a broken file
+        if length < 2 {
+            return None;
+        }
+
+        match marker {
+            // baseline, extended and progressive huffman
+            0xc0..=0xc2 => {
+                let height = u16::from_be_bytes([head[at + 5], head[at + 6]]) as u64;
+                let width = u16::from_be_bytes([head[at + 7], head[at + 8]]) as u64;
+                return Some(width * height);
+            }
+            // every other frame type, lossless among them
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => return None,
+            // the image data starts without a frame header, not a jpeg we want
+            0xda => return None,
+            _ => at += 2 + length,
+        }
+    }
+
+    None
+}
+
+// Just enough of a tiff reader to walk ifds
+struct Tiff<'a> {
+    file: &'a File,
+    little_endian: bool,
+    first_ifd: u64,
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    // the value itself when it fits in 4 bytes, otherwise where it is
+    field: [u8; 4],
+}
+
+struct Ifd {
+    entries: Vec<Entry>,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(file: &'a File) -> Result<Self, Error> {
+        let mut header = [0; 8];
+        file.read_exact_at(&mut header, 0)?;
+
+        let little_endian = match &header[..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => return Err("not a tiff based raw".into()),
+        };
+
+        let mut tiff = Tiff {
+            file,
+            little_endian,
+            first_ifd: 0,
+        };
+
+        // olympus and panasonic swap the 42 for a magic of their own
+        match tiff.u16(header[2..4].try_into().unwrap()) {
+            42 | 0x4f52 | 0x5352 | 0x55 => {}
+            _ => return Err("not a tiff based raw".into()),
+        }
+
+        tiff.first_ifd = tiff.u32(header[4..8].try_into().unwrap()) as u64;
+
+        Ok(tiff)
+    }
+
+    fn u16(&self, bytes: [u8; 2]) -> u16 {
+        if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: [u8; 4]) -> u32 {
+        if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn read_ifd(&self, offset: u64) -> Result<(Ifd, u64), Error> {
+        let mut count = [0; 2];
+        self.file.read_exact_at(&mut count, offset)?;
+        let count = self.u16(count) as usize;
+
+        let mut buf = vec![0; count * 12 + 4];
+        self.file.read_exact_at(&mut buf, offset + 2)?;
+
+        let entries = buf[..count * 12]
+            .chunks_exact(12)
+            .map(|entry| Entry {
+                tag: self.u16(entry[0..2].try_into().unwrap()),
+                kind: self.u16(entry[2..4].try_into().unwrap()),
+                count: self.u32(entry[4..8].try_into().unwrap()),
+                field: entry[8..12].try_into().unwrap(),
+            })
+            .collect();
+
+        let next = self.u32(buf[count * 12..].try_into().unwrap()) as u64;
+
+        Ok((Ifd { entries }, next))
+    }
+
+    // Every ifd in the file, the main chain first and then the sub ifds hanging off them
+    fn ifds(&self) -> Result<Vec<Ifd>, Error> {
+        let mut ifds = vec![];
+        let mut seen = HashSet::new();
+        let mut queue = vec![self.first_ifd];
+
+        while let Some(offset) = queue.pop() {
+            if offset == 0 || ifds.len() >= MAX_IFDS || !seen.insert(offset) {
+                continue;
+            }
+
+            let Ok((ifd, next)) = self.read_ifd(offset) else {
+                continue;
+            };
+
+            queue.push(next);
+            queue.extend(ifd.values(self, SUB_IFDS).into_iter().map(u64::from));
+
+            ifds.push(ifd);
+        }
+
+        if ifds.is_empty() {
+            return Err("raw has no readable ifds".into());
+        }
+
+        Ok(ifds)
+    }
+}
+
+impl Ifd {
+    fn entry(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    // first value of a short or long tag
+    fn value(&self, tiff: &Tiff, tag: u16) -> Option<u32> {
+        self.values(tiff, tag).first().copied()
+    }
+
+    // all values of a short or long tag, wherever they are stored
+    fn values(&self, tiff: &Tiff, tag: u16) -> Vec<u32> {
+        let Some(entry) = self.entry(tag) else {
+            return vec![];
+        };
+
+        let size = match entry.kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return vec![],
+        };
+
+        let count = (entry.count as usize).min(1024);
+
+        let bytes = if count * size <= 4 {
+            entry.field[..count * size].to_vec()
+        } else {
+            let mut bytes = vec![0; count * size];
+            let offset = tiff.u32(entry.field) as u64;
+
+            if tiff.file.read_exact_at(&mut bytes, offset).is_err() {
+                return vec![];
+            }
+
+            bytes
+        };
+
+        bytes
+            .chunks_exact(size)
+            .map(|value| match size {
+                2 => tiff.u16(value.try_into().unwrap()) as u32,
+                _ => tiff.u32(value.try_into().unwrap()),
+            })
+            .collect()
+    }
+
+    // Offset and length of every jpeg this ifd points at
+    fn jpegs(&self, tiff: &Tiff) -> Vec<(u64, u64)> {
+        let mut jpegs = vec![];
+
+        if let (Some(offset), Some(length)) =
+            (self.value(tiff, JPEG_OFFSET), self.value(tiff, JPEG_LENGTH))
+        {
+            jpegs.push((offset as u64, length as u64));
+        }
+
+        // old style jpeg and jpeg compression, as long as its a single strip.
+        // primary images (subfile type 0) are usually the raw data, but jpeg_area weeds those out
+        let compression = self.value(tiff, COMPRESSION);
+        if matches!(compression, Some(6) | Some(7)) {
+            let offsets = self.values(tiff, STRIP_OFFSETS);
+            let lengths = self.values(tiff, STRIP_BYTE_COUNTS);
+
+            if let ([offset], [length]) = (offsets.as_slice(), lengths.as_slice()) {
+                jpegs.push((*offset as u64, *length as u64));
+            }
+        }
+
+        if let Some(entry) = self.entry(RW2_JPEG) {
+            jpegs.push((tiff.u32(entry.field) as u64, entry.count as u64));
+        }
+
+        jpegs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    // the bytes as an open file, removed from disk right away since only the handle is needed
+    fn file(bytes: &[u8]) -> File {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "iv-raw-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        file
+    }
+
+    // a jpeg thats only headers, enough for jpeg_area to find its size
+    fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut jpeg = vec![0xff, 0xd8];
+        // an app0 segment to step over first
+        jpeg.extend([0xff, 0xe0, 0x00, 0x04, 0x00, 0x00]);
+        jpeg.extend([0xff, 0xc0, 0x00, 0x11, 0x08]);
+        jpeg.extend(height.to_be_bytes());
+        jpeg.extend(width.to_be_bytes());
+        jpeg.extend([0; 12]);
+        jpeg.extend([0xff, 0xd9]);
+        jpeg
+    }
+
+    // a little endian ifd out of (tag, kind, count, field) entries
+    fn ifd(entries: &[(u16, u16, u32, u32)], next: u32) -> Vec<u8> {
+        let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+
+        for (tag, kind, count, field) in entries {
+            ifd.extend(tag.to_le_bytes());
+            ifd.extend(kind.to_le_bytes());
+            ifd.extend(count.to_le_bytes());
+            ifd.extend(field.to_le_bytes());
+        }
+
+        ifd.extend(next.to_le_bytes());
+        ifd
+    }
+
+    fn tiff(magic: u16, first_ifd: u32) -> Vec<u8> {
+        let mut tiff = b"II".to_vec();
+        tiff.extend(magic.to_le_bytes());
+        tiff.extend(first_ifd.to_le_bytes());
+        tiff
+    }
+
+    fn jpeg_tags(offset: usize, jpeg: &[u8]) -> [(u16, u16, u32, u32); 2] {
+        [
+            (JPEG_OFFSET, 4, 1, offset as u32),
+            (JPEG_LENGTH, 4, 1, jpeg.len() as u32),
+        ]
+    }
+
+    #[test]
+    fn truncated_ifd() {
+        // says it has 5 entries, then ends
+        let mut bytes = tiff(42, 8);
+        bytes.extend(5u16.to_le_bytes());
+
+        let file = file(&bytes);
+        assert!(Tiff::new(&file).unwrap().ifds().is_err());
+
+        // points past the end of the file
+        let file = self::file(&tiff(42, 4096));
+        assert!(Tiff::new(&file).unwrap().ifds().is_err());
+
+        // too short to even be a tiff
+        assert!(Tiff::new(&self::file(b"II*")).is_err());
+    }
+
+    #[test]
+    fn ifd_loop() {
+        let preview = jpeg(160, 120);
+
+        // the first ifd points at a second one, which points back at the first
+        let first = 8;
+        let second = first + 2 + 2 * 12 + 4;
+        let at = second + 2 + 4;
+
+        let mut bytes = tiff(42, first as u32);
+        bytes.extend(ifd(&jpeg_tags(at, &preview), second as u32));
+        bytes.extend(ifd(&[], first as u32));
+        bytes.extend(&preview);
+
+        let file = file(&bytes);
+        assert_eq!(Tiff::new(&file).unwrap().ifds().unwrap().len(), 2);
+
+        // and one pointing at itself
+        let mut bytes = tiff(42, 8);
+        bytes.extend(ifd(&[], 8));
+
+        let file = self::file(&bytes);
+        assert_eq!(Tiff::new(&file).unwrap().ifds().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sub_ifds() {
+        let small = jpeg(160, 120);
+        let big = jpeg(1600, 1200);
+
+        // ifd0 has the small preview, orientation and a sub ifd with the big preview
+        let first = 8;
+        let sub = first + 2 + 4 * 12 + 4;
+        let small_at = sub + 2 + 2 * 12 + 4;
+        let big_at = small_at + small.len();
+
+        let mut entries = jpeg_tags(small_at, &small).to_vec();
+        entries.push((ORIENTATION, 3, 1, 6));
+        entries.push((SUB_IFDS, 4, 1, sub as u32));
+
+        let mut bytes = tiff(42, first as u32);
+        bytes.extend(ifd(&entries, 0));
+        bytes.extend(ifd(&jpeg_tags(big_at, &big), 0));
+        bytes.extend(&small);
+        bytes.extend(&big);
+
+        let file = file(&bytes);
+        let tiff = Tiff::new(&file).unwrap();
+        assert_eq!(tiff.ifds().unwrap().len(), 2);
+
+        let path = std::env::temp_dir().join(format!("iv-raw-sub-ifds-{}", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let preview = Preview::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let preview = preview.unwrap();
+        assert_eq!(preview.jpeg, big);
+        assert_eq!(preview.orientation, Orientation::Rotate90);
+    }
+
+    #[test]
+    fn rw2_jpeg() {
+        let preview = jpeg(640, 480);
+        let at = 8 + 2 + 12 + 4;
+
+        // panasonic's own magic, the tag holds the offset and its count the length
+        let mut bytes = tiff(0x55, 8);
+        bytes.extend(ifd(&[(RW2_JPEG, 7, preview.len() as u32, at as u32)], 0));
+        bytes.extend(&preview);
+
+        let file = file(&bytes);
+        let tiff = Tiff::new(&file).unwrap();
+        let ifds = tiff.ifds().unwrap();
+
+        assert_eq!(
+            ifds[0].jpegs(&tiff),
+            vec![(at as u64, preview.len() as u64)]
+        );
+    }
+
+    #[test]
+    fn raf_header() {
+        let preview = jpeg(320, 240);
+        let at = 100;
+
+        let mut bytes = b"FUJIFILMCCD-RAW ".to_vec();
+        bytes.resize(84, 0);
+        bytes.extend((at as u32).to_be_bytes());
+        bytes.extend((preview.len() as u32).to_be_bytes());
+        bytes.resize(at, 0);
+        bytes.extend(&preview);
+
+        let preview_read = Preview::read_raf(&file(&bytes)).unwrap();
+        assert_eq!(preview_read.jpeg, preview);
+
+        // a length running past the end of the file
+        let mut bytes = bytes.clone();
+        bytes[88..92].copy_from_slice(&4096u32.to_be_bytes());
+        assert!(Preview::read_raf(&file(&bytes)).is_err());
+
+        // an offset that isnt a jpeg
+        bytes[84..88].copy_from_slice(&20u32.to_be_bytes());
+        assert!(Preview::read_raf(&file(&bytes)).is_err());
+    }
+
+    #[test]
+    fn sof_markers() {
+        assert_eq!(jpeg_area(&file(&jpeg(40, 30)), 0), Some(1200));
+
+        // at an offset into the file
+        let mut bytes = vec![0; 10];
+        bytes.extend(jpeg(40, 30));
+        assert_eq!(jpeg_area(&file(&bytes), 10), Some(1200));
+
+        // lossless, like the raw data itself
+        let mut lossless = jpeg(40, 30);
+        lossless[9] = 0xc3;
+        assert_eq!(jpeg_area(&file(&lossless), 0), None);
+
+        // image data before any frame header
+        let mut scan = jpeg(40, 30);
+        scan[9] = 0xda;
+        assert_eq!(jpeg_area(&file(&scan), 0), None);
+
+        // a zero length segment cant be stepped over, and mustnt be walked forever
+        let mut zero = jpeg(40, 30);
+        zero[4..6].copy_from_slice(&[0, 0]);
+        assert_eq!(jpeg_area(&file(&zero), 0), None);
+
+        // not a jpeg at all
+        assert_eq!(jpeg_area(&file(&[0; 32]), 0), None);
+    }
+}
@@ -0,0 +1,49 @@
+use serde::Deserialize;
+
+use crate::db::FileRecord;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadMode {
+    #[default]
+    Single,
+    // two pages side by side like a printed book, the cover on its own
+    Spread,
+    // every page stacked, for webtoons and storyboards
+    Scroll,
+}
+
+impl ReadMode {
+    pub const ALL: [ReadMode; 3] = [ReadMode::Single, ReadMode::Spread, ReadMode::Scroll];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadMode::Single => "single",
+            ReadMode::Spread => "spread",
+            ReadMode::Scroll => "scroll",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|known| known.as_str() == mode)
+    }
+}
+
+// Where someone left off reading a dir, and how they were reading it
+#[derive(Debug, Clone, Default)]
+pub struct Reading {
+    // file name of the page, so pages added in front of it or another sort dont move the bookmark
+    pub page: Option<String>,
+    pub mode: ReadMode,
+    pub rtl: bool,
+}
+
+impl Reading {
+    // index of the bookmarked page, the first one if its gone or there isnt one
+    pub fn position(&self, pages: &[FileRecord]) -> usize {
+        self.page
+            .as_ref()
+            .and_then(|page| pages.iter().position(|record| record.name() == *page))
+            .unwrap_or(0)
+    }
+}
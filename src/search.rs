@@ -0,0 +1,60 @@
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+// how deep /!search descends below the root when traversal is allowed
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    #[default]
+    Substring,
+    Glob,
+    Regex,
+}
+
+impl SearchMode {
+    pub const ALL: [SearchMode; 3] = [SearchMode::Substring, SearchMode::Glob, SearchMode::Regex];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchMode::Substring => "substring",
+            SearchMode::Glob => "glob",
+            SearchMode::Regex => "regex",
+        }
+    }
+}
+
+// A compiled search, matched against file names, always case insensitive
+pub enum Matcher {
+    Substring(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(q: &str, mode: SearchMode) -> Result<Self, String> {
+        match mode {
+            SearchMode::Substring => Ok(Matcher::Substring(q.to_lowercase())),
+            SearchMode::Glob => GlobBuilder::new(q)
+                .case_insensitive(true)
+                .build()
+                .map(|glob| Matcher::Glob(glob.compile_matcher()))
+                .map_err(|err| err.to_string()),
+            SearchMode::Regex => RegexBuilder::new(q)
+                .case_insensitive(true)
+                .build()
+                .map(Matcher::Regex)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            Matcher::Substring(q) => name.to_lowercase().contains(q.as_str()),
+            Matcher::Glob(glob) => glob.is_match(name),
+            Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
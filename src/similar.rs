@@ -0,0 +1,252 @@
+use std::path::Path;
+
+use image::{imageops::FilterType, DynamicImage};
+
+use crate::{
+    convert,
+    db::{Error, FileRecord},
+};
+
+// Difference hash: shrink to 9x8 greyscale and record whether each pixel is brighter than
+// the one to its right. survives resizing and re-encoding, not cropping or rotation
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+// Decodes the whole image, so call it from a blocking context
+pub fn dhash_file(path: &Path) -> Result<u64, Error> {
+    Ok(dhash(&convert::decode(path)?))
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// BK-tree over the hamming distance between hashes, so finding the hashes near one only looks at
+// a small part of them instead of comparing against every single one
+struct BkTree {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    hash: u64,
+    // indices of every hash equal to this one
+    indices: Vec<usize>,
+    // (distance from this node, index into nodes), at most one child per distance
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn new(hashes: &[u64]) -> Self {
+        let mut tree = BkTree { nodes: vec![] };
+
+        for (index, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, index);
+        }
+
+        tree
+    }
+
+    fn insert(&mut self, hash: u64, index: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                hash,
+                indices: vec![index],
+                children: vec![],
+            });
+            return;
+        }
+
+        let mut current = 0;
+
+        loop {
+            let d = distance(self.nodes[current].hash, hash);
+
+            if d == 0 {
+                self.nodes[current].indices.push(index);
+                return;
+            }
+
+            match self.nodes[current]
+                .children
+                .iter()
+                .find(|(child_distance, _)| *child_distance == d)
+            {
+                Some((_, child)) => current = *child,
+                None => {
+                    self.nodes.push(Node {
+                        hash,
+                        indices: vec![index],
+                        children: vec![],
+                    });
+
+                    let child = self.nodes.len() - 1;
+                    self.nodes[current].children.push((d, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    // Calls visit with the index of every hash within max_distance of hash
+    fn within(&self, hash: u64, max_distance: u32, mut visit: impl FnMut(usize)) {
+        let mut stack = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            let d = distance(node.hash, hash);
+
+            if d <= max_distance {
+                node.indices.iter().copied().for_each(&mut visit);
+            }
+
+            // by the triangle inequality nothing under a child further off than this can match
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(child_distance, _)| child_distance.abs_diff(d) <= max_distance)
+                    .map(|(_, child)| *child),
+            );
+        }
+    }
+}
+
+// Groups indices of hashes around the hash with the most others near it, then around the one
+// with the most of whats left and so on. every hash in a group is within max_distance of the
+// first one, so a chain of small steps cant pull very different images into the same group
+fn group(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let tree = BkTree::new(hashes);
+
+    let mut counts = vec![0; hashes.len()];
+
+    for (i, hash) in hashes.iter().enumerate() {
+        tree.within(*hash, max_distance, |_| counts[i] += 1);
+    }
+
+    let mut order: Vec<usize> = (0..hashes.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(counts[*i]));
+
+    let mut taken = vec![false; hashes.len()];
+    let mut groups = vec![];
+
+    for centre in order {
+        if taken[centre] {
+            continue;
+        }
+
+        let mut group = vec![centre];
+
+        tree.within(hashes[centre], max_distance, |i| {
+            if i != centre && !taken[i] {
+                group.push(i);
+            }
+        });
+
+        // an image with nothing left near it might still belong with a centre further down
+        if group.len() > 1 {
+            group.iter().for_each(|i| taken[*i] = true);
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+// Groups images whose hashes are within max_distance of the first image in the group.
+// only groups with more than one image are returned, biggest first
+pub fn cluster(records: Vec<FileRecord>, max_distance: u32) -> Vec<Vec<FileRecord>> {
+    let hashes: Vec<u64> = records
+        .iter()
+        .map(|record| record.phash.unwrap_or(0))
+        .collect();
+
+    let mut records: Vec<Option<FileRecord>> = records.into_iter().map(Some).collect();
+
+    let mut groups: Vec<Vec<FileRecord>> = group(&hashes, max_distance)
+        .into_iter()
+        .map(|group| {
+            group
+                .into_iter()
+                .filter_map(|i| records[i].take())
+                .collect()
+        })
+        .collect();
+
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn within_matches_brute_force() {
+        // a cheap xorshift, so the hashes are spread out but the same every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let hashes: Vec<u64> = (0..500)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                // keep most bits equal so plenty of them land close together
+                state & 0xffff
+            })
+            .collect();
+
+        let tree = BkTree::new(&hashes);
+
+        for max_distance in [0, 3, 8] {
+            for hash in &hashes[..50] {
+                let mut found = vec![];
+                tree.within(*hash, max_distance, |i| found.push(i));
+                found.sort();
+
+                let expected: Vec<usize> = (0..hashes.len())
+                    .filter(|i| distance(hashes[*i], *hash) <= max_distance)
+                    .collect();
+
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn groups_dont_chain() {
+        // each hash is 2 bits from the next, so the ends are 8 bits apart
+        let hashes = [0b0, 0b11, 0b1111, 0b11_1111, 0b1111_1111];
+
+        for group in group(&hashes, 2) {
+            for a in &group {
+                assert!(distance(hashes[group[0]], hashes[*a]) <= 2);
+            }
+        }
+
+        let mut everything = group(&hashes, 8);
+        everything[0].sort();
+        assert_eq!(everything, vec![vec![0, 1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn lone_images_arent_grouped() {
+        assert!(group(&[0, u64::MAX], 10).is_empty());
+        assert_eq!(group(&[7, 7], 0), vec![vec![0, 1]]);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{fs::File, io::Read, path::Path};
+
+use crate::{archive, partials::FileType, raw};
+
+// enough for every signature infer knows short of disk images
+const HEADER_BYTES: u64 = 8 * 1024;
+
+// mpeg transport streams are 188 byte packets that each start with this
+const TS_SYNC: u8 = 0x47;
+const TS_PACKET: usize = 188;
+
+// Signatures shared by many formats, where a known extension says more than the bytes do.
+// raws are tiff, cbz and docx are zip, m4a is mp4 and so on
+const CONTAINERS: [&str; 9] = [
+    "application/zip",
+    "application/gzip",
+    "application/x-ole-storage",
+    "audio/ogg",
+    "image/tiff",
+    "video/mp4",
+    "video/quicktime",
+    "video/x-m4v",
+    "video/x-matroska",
+];
+
+// What a file is going by its first bytes, with the extension as a fallback. this reads the
+// start of the file, listings get it from the index instead (see FileRecord::compute_details)
+pub fn file_type(path: &Path) -> FileType {
+    let by_extension = FileType::from(&path.to_path_buf());
+
+    // members would have to be unpacked for this, their extension will have to do
+    if by_extension == FileType::Dir || archive::member(path).is_some() {
+        return by_extension;
+    }
+
+    let mut head = vec![];
+
+    let read = File::open(path).and_then(|file| file.take(HEADER_BYTES).read_to_end(&mut head));
+
+    if read.is_err() {
+        return by_extension;
+    }
+
+    match sniff(&head) {
+        Some(mime) if prefer(mime, path, &by_extension) => FileType::from_mime(mime),
+        _ => by_extension,
+    }
+}
+
+// Whether the sniffed mime should win over the one the extension gives
+fn prefer(sniffed: &str, path: &Path, by_extension: &FileType) -> bool {
+    let unknown = by_extension.mime() == "application/octet-stream";
+
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or_default();
+
+    // the raw table knows which camera a tiff came from, the signature doesnt
+    if raw::mime(ext).is_some() {
+        return false;
+    }
+
+    // text signatures are just a guess at the first few characters, an svg starts like any
+    // other xml and scripts with a shebang can be in any language
+    if sniffed.starts_with("text/") || CONTAINERS.contains(&sniffed) {
+        return unknown;
+    }
+
+    true
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    // infer doesnt know transport streams, which share the .ts extension with typescript
+    if head.len() > 2 * TS_PACKET && (0..3).all(|packet| head[packet * TS_PACKET] == TS_SYNC) {
+        return Some("video/mp2t");
+    }
+
+    if let Some(kind) =
+        infer::get(head).filter(|kind| kind.mime_type() != "application/octet-stream")
+    {
+        return Some(kind.mime_type());
+    }
+
+    looks_like_text(head).then_some("text/plain")
+}
+
+// no nul bytes and valid utf-8, apart from a character cut off at the end of the header
+fn looks_like_text(head: &[u8]) -> bool {
+    !head.is_empty()
+        && !head.contains(&0)
+        && match std::str::from_utf8(head) {
+            Ok(_) => true,
+            Err(err) => err.error_len().is_none(),
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0";
+    const TIFF: &[u8] = b"II*\0\x08\0\0\0\0\0\0\0\0\0\0\0";
+
+    // the bytes saved under name, and what file_type makes of them
+    fn sniffed(name: &str, bytes: &[u8]) -> String {
+        let dir = std::env::temp_dir().join(format!("iv-sniff-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        let mime = file_type(&path).mime().to_string();
+        std::fs::remove_file(&path).unwrap();
+
+        mime
+    }
+
+    fn transport_stream() -> Vec<u8> {
+        let mut ts = vec![0; TS_PACKET * 4];
+        for packet in ts.chunks_mut(TS_PACKET) {
+            packet[0] = TS_SYNC;
+        }
+        ts
+    }
+
+    #[test]
+    fn misnamed_image() {
+        let path = PathBuf::from("photo.jpg");
+
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert!(prefer("image/png", &path, &FileType::from(&path)));
+        assert_eq!(sniffed("photo.jpg", PNG), "image/png");
+    }
+
+    #[test]
+    fn transport_stream_or_typescript() {
+        let ts = transport_stream();
+
+        assert_eq!(sniff(&ts), Some("video/mp2t"));
+        assert_eq!(sniffed("clip.ts", &ts), "video/mp2t");
+
+        // source code only looks like text, so the extension keeps saying typescript
+        let source = b"const x: number = 1;\n";
+        assert_eq!(sniff(source), Some("text/plain"));
+        assert_eq!(sniffed("app.ts", source), "text/typescript");
+
+        // too short to tell packets apart from chance
+        assert_eq!(sniff(&ts[..TS_PACKET * 2]), None);
+    }
+
+    #[test]
+    fn extensionless_text() {
+        assert_eq!(sniffed("notes", b"just some notes\n"), "text/plain");
+        assert_eq!(sniffed("picture", PNG), "image/png");
+
+        // a character cut in half by the end of the header is still text, a nul isnt
+        assert!(looks_like_text(
+            "caf\u{e9}".as_bytes().split_last().unwrap().1
+        ));
+        assert!(!looks_like_text(b"text\0with a nul"));
+        assert!(!looks_like_text(b"\xff\xfe\xfd not utf-8"));
+        assert!(!looks_like_text(b""));
+    }
+
+    #[test]
+    fn raw_holding_tiff() {
+        let path = PathBuf::from("DSC_0001.NEF");
+
+        assert_eq!(sniff(TIFF), Some("image/tiff"));
+        assert!(!prefer("image/tiff", &path, &FileType::from(&path)));
+        assert_eq!(sniffed("DSC_0001.NEF", TIFF), "image/x-nikon-nef");
+
+        // a tiff with nothing else to go on is just a tiff
+        assert_eq!(sniffed("scan", TIFF), "image/tiff");
+    }
+}
@@ -0,0 +1,254 @@
+use std::{fs::File, io::Read, path::Path};
+
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+};
+
+use crate::{
+    archive,
+    db::{Error, FileRecord},
+};
+
+// past this a file is cut off, a preview of a gigabyte log is no use to anyone
+pub const MAX_BYTES: u64 = 256 * 1024;
+
+// how much of a file the card on the grid shows
+const SNIPPET_BYTES: u64 = 1024;
+const SNIPPET_LINES: usize = 8;
+
+// text mimes that dont start with text/
+const TEXT_MIMES: [&str; 7] = [
+    "application/json",
+    "application/javascript",
+    "application/x-javascript",
+    "application/x-sh",
+    "application/x-sql",
+    "application/x-subrip",
+    "application/xml",
+];
+
+lazy_static::lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEME: Theme = ThemeSet::load_defaults().themes["base16-ocean.dark"].clone();
+}
+
+pub fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/") || TEXT_MIMES.contains(&mime)
+}
+
+// A text file decoded to a string, or as much of one as fits under the cap
+pub struct Text {
+    pub text: String,
+    pub encoding: &'static str,
+    pub truncated: bool,
+}
+
+impl Text {
+    pub fn read(path: &Path, limit: u64) -> Result<Self, Error> {
+        let mut bytes = vec![];
+
+        // members of archives come out of the zip reader, but just as capped
+        if archive::member(path).is_some() {
+            bytes = archive::read_head(path, limit + 1)?;
+        } else {
+            File::open(path)?.take(limit + 1).read_to_end(&mut bytes)?;
+        }
+
+        let truncated = bytes.len() as u64 > limit;
+        let bom = Encoding::for_bom(&bytes).map(|(encoding, _)| encoding);
+
+        if truncated {
+            bytes.truncate(limit as usize);
+
+            // dont leave half a line (or half a character) at the end. utf-16 newlines are a
+            // whole code unit, which byte of it is the 0x0a depends on the byte order
+            let newline = match bom {
+                Some(encoding) if encoding == UTF_16LE => Some([b'\n', 0]),
+                Some(encoding) if encoding == UTF_16BE => Some([0, b'\n']),
+                _ => None,
+            };
+
+            if let Some(newline) = newline {
+                let unit = bytes.chunks_exact(2).rposition(|unit| unit == newline);
+                bytes.truncate(unit.map_or(bytes.len() & !1, |unit| (unit + 1) * 2));
+            } else if let Some(newline) = bytes.iter().rposition(|&byte| byte == b'\n') {
+                bytes.truncate(newline + 1);
+            }
+        }
+
+        // nul bytes dont show up in text in any encoding worth guessing, except utf-16 which is
+        // full of them, but that only gets decoded with a bom
+        if bom.is_none() && bytes.contains(&0) {
+            return Err("binary file".into());
+        }
+
+        let (text, encoding) = decode(&bytes, !truncated);
+
+        Ok(Text {
+            text,
+            encoding: encoding.name(),
+            truncated,
+        })
+    }
+}
+
+// a bom says for sure, otherwise its a guess from the bytes themselves
+fn decode(bytes: &[u8], complete: bool) -> (String, &'static Encoding) {
+    let encoding = Encoding::for_bom(bytes)
+        .map(|(encoding, _)| encoding)
+        .unwrap_or_else(|| {
+            let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+            detector.feed(bytes, complete);
+            detector.guess(None, Utf8Detection::Allow)
+        });
+
+    let (text, encoding, _) = encoding.decode(bytes);
+
+    (text.into_owned(), encoding)
+}
+
+fn syntax(path: &Path, text: &str) -> &'static SyntaxReference {
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or_default();
+
+    // the bundled syntaxes dont know typescript, javascript gets most of it right
+    let ext = match ext {
+        "ts" | "mts" | "cts" => "js",
+        ext => ext,
+    };
+
+    SYNTAXES
+        .find_syntax_by_extension(ext)
+        .or_else(|| {
+            path.file_name()
+                .and_then(|name| SYNTAXES.find_syntax_by_extension(&name.to_string_lossy()))
+        })
+        // shebangs and the like
+        .or_else(|| SYNTAXES.find_syntax_by_first_line(text.lines().next().unwrap_or_default()))
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text())
+}
+
+// Name of the syntax a file is highlighted as
+pub fn syntax_name(path: &Path, text: &str) -> &'static str {
+    &syntax(path, text).name
+}
+
+// Highlights a file into one html fragment per line, for putting next to line numbers.
+// this is slow for big files, so call it from a blocking context
+pub fn highlight(path: &Path, text: &str) -> Vec<String> {
+    let mut highlighter = HighlightLines::new(syntax(path, text), &THEME);
+
+    text.lines()
+        .map(|line| {
+            // the syntaxes expect the newline to still be there
+            let line = format!("{}\n", line);
+
+            highlighter
+                .highlight_line(&line, &SYNTAXES)
+                .ok()
+                .and_then(|regions| {
+                    styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()
+                })
+                .unwrap_or_else(|| html_escape(&line))
+        })
+        .collect()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// The first few lines of a text file for its card, nothing if it doesnt look like text.
+// this reads the file, so call it from a blocking context
+pub fn snippet(record: &FileRecord) -> Option<String> {
+    // reading members means unpacking them, which is too much for a card
+    if record.archived {
+        return None;
+    }
+
+    let text = Text::read(&record.path, SNIPPET_BYTES).ok()?;
+
+    Some(
+        text.text
+            .lines()
+            .take(SNIPPET_LINES)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(name: &str, bytes: &[u8], limit: u64) -> Result<Text, Error> {
+        let path =
+            std::env::temp_dir().join(format!("iv-text-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let text = Text::read(&path, limit);
+        std::fs::remove_file(&path).unwrap();
+        text
+    }
+
+    fn utf16(bom: &[u8], text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        let mut bytes = bom.to_vec();
+        bytes.extend(text.encode_utf16().flat_map(to_bytes));
+        bytes
+    }
+
+    #[test]
+    fn utf16_with_bom() {
+        let text = "h\u{e9}llo\nw\u{f6}rld \u{1f600}\n";
+
+        let le = read(
+            "le",
+            &utf16(&[0xff, 0xfe], text, u16::to_le_bytes),
+            MAX_BYTES,
+        )
+        .unwrap();
+        assert_eq!(le.text, text);
+        assert_eq!(le.encoding, "UTF-16LE");
+        assert!(!le.truncated);
+
+        let be = read(
+            "be",
+            &utf16(&[0xfe, 0xff], text, u16::to_be_bytes),
+            MAX_BYTES,
+        )
+        .unwrap();
+        assert_eq!(be.text, text);
+        assert_eq!(be.encoding, "UTF-16BE");
+    }
+
+    #[test]
+    fn utf16_cut_at_a_newline() {
+        // the cap lands inside the second line, which is dropped whole
+        let bytes = utf16(&[0xff, 0xfe], "one\ntwo\nthree", u16::to_le_bytes);
+        let text = read("cut-le", &bytes, 13).unwrap();
+        assert_eq!(text.text, "one\n");
+        assert!(text.truncated);
+
+        let bytes = utf16(&[0xfe, 0xff], "one\ntwo\nthree", u16::to_be_bytes);
+        assert_eq!(read("cut-be", &bytes, 19).unwrap().text, "one\ntwo\n");
+
+        // without a newline it still doesnt leave half a code unit
+        let bytes = utf16(&[0xff, 0xfe], "abcdef", u16::to_le_bytes);
+        assert_eq!(read("cut-none", &bytes, 9).unwrap().text, "abc");
+    }
+
+    #[test]
+    fn nul_without_bom() {
+        assert!(read("nul", b"text\0more", MAX_BYTES).is_err());
+        assert!(read("nul-utf8-bom", b"\xef\xbb\xbftext\0", MAX_BYTES).is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    fs::Metadata,
+    io::BufWriter,
+    os::linux::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat};
+
+use crate::{
+    archive, audio, cache_root, convert,
+    db::FileRecord,
+    fnv1a64,
+    partials::{url_path, FileType},
+    sniff, Args,
+};
+
+// bump when thumbnails come out differently, so cached ones (and the browsers copies) get redone
+const THUMB_VERSION: u32 = 2;
+
+// fixed set of sizes, so the cache doesnt explode with every possible width
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbSize {
+    // only used for the blurred card background, so it can be tiny
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbSize {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sm" => Some(ThumbSize::Small),
+            "md" => Some(ThumbSize::Medium),
+            "lg" => Some(ThumbSize::Large),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThumbSize::Small => "sm",
+            ThumbSize::Medium => "md",
+            ThumbSize::Large => "lg",
+        }
+    }
+
+    pub fn pixels(&self) -> u32 {
+        match self {
+            ThumbSize::Small => 64,
+            ThumbSize::Medium => 320,
+            ThumbSize::Large => 640,
+        }
+    }
+}
+
+// url for a thumbnail of a file, the mtime is only there so browsers refetch edited files
+pub fn thumb_url(size: ThumbSize, record: &FileRecord) -> String {
+    format!(
+        "/!thumb/{}/{}?v={:x}.{}",
+        size.as_str(),
+        url_path(&record.path),
+        record.mtime,
+        THUMB_VERSION
+    )
+}
+
+pub fn cache_dir(args: &Args) -> PathBuf {
+    cache_root(args).join("thumbs")
+}
+
+// cache key is the canonical path + mtime + variant, so edited files get a fresh thumbnail
+fn cache_key(path: &Path, meta: &Metadata, variant: &str) -> String {
+    let key = format!(
+        "{}\0{}.{}\0{}\0{}\0{}",
+        path.to_string_lossy(),
+        meta.st_mtime(),
+        meta.st_mtime_nsec(),
+        meta.st_size(),
+        variant,
+        THUMB_VERSION
+    );
+
+    format!("{:0>16x}", fnv1a64(key.as_bytes()))
+}
+
+// where a generated variant of a file lives in a cache dir, without an extension
+pub fn cached_path(dir: &Path, path: &Path, meta: &Metadata, variant: &str) -> PathBuf {
+    let key = cache_key(path, meta, variant);
+
+    // shard by the first byte so a single directory doesnt end up with 100k files
+    dir.join(&key[..2]).join(key)
+}
+
+// A temp file next to out for one writer to fill before renaming it into place. two requests
+// for the same file at once (like two tabs) would write into each other with a shared name
+pub fn temp_path(out: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    out.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// thumbnails with transparency are stored as png, everything else as jpeg
+const EXTENSIONS: [&str; 2] = ["jpg", "png"];
+
+fn encode(img: &DynamicImage, out: &Path) -> image::ImageResult<PathBuf> {
+    // write to a temp file first, so a half written thumbnail never gets served, and dont
+    // leave it lying around if encoding fails
+    let tmp = temp_path(out);
+
+    let write = || -> image::ImageResult<PathBuf> {
+        let mut writer = BufWriter::new(std::fs::File::create(&tmp)?);
+
+        if img.color().has_alpha() {
+            img.write_to(&mut writer, ImageFormat::Png)?;
+            Ok(out.with_extension("png"))
+        } else {
+            JpegEncoder::new_with_quality(&mut writer, 80).encode_image(&img.to_rgb8())?;
+            Ok(out.with_extension("jpg"))
+        }
+    };
+
+    let out = write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })?;
+
+    std::fs::rename(&tmp, &out)?;
+
+    Ok(out)
+}
+
+// Returns the path to the cached thumbnail, generating it if needed.
+// This decodes the whole image (or cover art), so call it from a blocking context
+pub fn get_or_create(
+    args: &Args,
+    path: &Path,
+    size: ThumbSize,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let meta = archive::metadata(path)?;
+    let out = cached_path(&cache_dir(args), path, &meta, size.as_str());
+
+    for ext in EXTENSIONS {
+        let cached = out.with_extension(ext);
+        if cached.exists() {
+            log::trace!("thumbnail cache hit: {:?} -> {:?}", path, cached);
+            return Ok(cached);
+        }
+    }
+
+    std::fs::create_dir_all(out.parent().unwrap())?;
+
+    let img = match sniff::file_type(path) {
+        FileType::Audio(_) => audio::cover(path)?,
+        _ => convert::decode(path)?,
+    };
+
+    let thumb = if img.width() > size.pixels() || img.height() > size.pixels() {
+        img.thumbnail(size.pixels(), size.pixels())
+    } else {
+        img
+    };
+
+    let out = encode(&thumb, &out)?;
+
+    log::debug!("generated thumbnail: {:?} -> {:?}", path, out);
+
+    Ok(out)
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+// proxies and browsers drop idle connections, so say something every now and then
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    // "update" if the file exists (created, modified or renamed into the dir), "remove" if it doesnt
+    #[serde(skip)]
+    pub event: &'static str,
+    pub name: String,
+}
+
+type Subscribers = Arc<Mutex<HashMap<PathBuf, Vec<UnboundedSender<Change>>>>>;
+
+// Watches directories that have a page open on them, and fans changes out to those pages
+pub struct Watcher {
+    watcher: Mutex<RecommendedWatcher>,
+    subscribers: Subscribers,
+}
+
+impl Watcher {
+    pub fn new() -> notify::Result<Self> {
+        let subscribers: Subscribers = Default::default();
+
+        let watcher = notify::recommended_watcher({
+            let subscribers = subscribers.clone();
+            move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => dispatch(&subscribers, event),
+                Err(err) => log::warn!("watch error: {}", err),
+            }
+        })?;
+
+        Ok(Watcher {
+            watcher: Mutex::new(watcher),
+            subscribers,
+        })
+    }
+
+    // Starts watching dir if nobody was yet, the receiver gets every change directly in it
+    pub fn subscribe(&self, dir: &Path) -> UnboundedReceiver<Change> {
+        let (tx, rx) = unbounded_channel();
+
+        // the watcher lock is held throughout so watch/unwatch calls happen in order, but
+        // subscribers isnt, since the watcher waits on its event thread which may be in dispatch
+        let mut watcher = self.watcher.lock().unwrap();
+
+        let (unwatch, watch) = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+
+            // closed pages only get noticed here or when an event comes in
+            let mut unwatch = vec![];
+            subscribers.retain(|dir, senders| {
+                senders.retain(|sender| !sender.is_closed());
+
+                if senders.is_empty() {
+                    unwatch.push(dir.clone());
+                }
+
+                !senders.is_empty()
+            });
+
+            let watch = !subscribers.contains_key(dir);
+            subscribers.entry(dir.to_path_buf()).or_default().push(tx);
+
+            (unwatch, watch)
+        };
+
+        for dir in unwatch {
+            log::debug!("unwatching {:?}", dir);
+            watcher.unwatch(&dir).unwrap_or(());
+        }
+
+        if watch {
+            log::debug!("watching {:?}", dir);
+
+            if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                log::warn!("failed to watch {:?}: {}", dir, err);
+            }
+        }
+
+        rx
+    }
+}
+
+fn dispatch(subscribers: &Subscribers, event: notify::Event) {
+    // reads dont change anything, and closing after a write already showed up as a modify
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+
+    let mut subscribers = subscribers.lock().unwrap();
+
+    // renames and the like are reported differently on every platform,
+    // so just look at whether the file is still there
+    for path in event.paths {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+
+        let Some(senders) = subscribers.get_mut(dir) else {
+            continue;
+        };
+
+        let change = Change {
+            event: if path.exists() { "update" } else { "remove" },
+            name: name.to_string_lossy().to_string(),
+        };
+
+        log::trace!("{} {:?}", change.event, path);
+
+        senders.retain(|sender| sender.send(change.clone()).is_ok());
+    }
+}
+
+// Turns a subscription into a text/event-stream body
+pub fn event_stream(
+    rx: UnboundedReceiver<Change>,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let keepalive = tokio::time::interval(KEEPALIVE);
+
+    stream::unfold((rx, keepalive), |(mut rx, mut keepalive)| async move {
+        let message = tokio::select! {
+            change = rx.recv() => {
+                let change = change?;
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    change.event,
+                    serde_json::to_string(&change).unwrap()
+                )
+            }
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+
+        Some((Ok(Bytes::from(message)), (rx, keepalive)))
+    })
+}